@@ -1,57 +1,123 @@
 #![windows_subsystem = "windows"]
 
 use anyhow::{Context, anyhow, bail};
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use directories::ProjectDirs;
 use itertools::Itertools;
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    cell::Cell,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
     env,
-    ffi::CString,
+    fmt::Display,
     fs,
     io::{ErrorKind, Write},
+    net::SocketAddr,
     path::{Path, PathBuf},
     rc::Rc,
-    sync::{Arc, RwLock},
+    sync::{Arc, LazyLock, Mutex, OnceLock, RwLock},
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::UnboundedSender;
 use windows::{
     Data::Xml::Dom::{XmlDocument, XmlElement},
-    Foundation::TypedEventHandler,
-    Graphics::Imaging::BitmapDecoder,
-    Media::Control::{
-        GlobalSystemMediaTransportControlsSession, GlobalSystemMediaTransportControlsSessionManager, GlobalSystemMediaTransportControlsSessionMediaProperties,
+    Foundation::{DateTime, TimeSpan, TypedEventHandler, Uri},
+    Graphics::Imaging::{
+        BitmapAlphaMode, BitmapDecoder, BitmapEncoder, BitmapInterpolationMode, BitmapPixelFormat, BitmapTransform, ColorManagementMode, ExifOrientationMode,
     },
-    Storage::Streams::DataReader,
-    UI::Notifications::{ToastNotification, ToastNotificationManager, ToastTemplateType},
+    Media::{
+        Control::{
+            GlobalSystemMediaTransportControlsSession, GlobalSystemMediaTransportControlsSessionManager,
+            GlobalSystemMediaTransportControlsSessionMediaProperties, GlobalSystemMediaTransportControlsSessionPlaybackStatus,
+        },
+        MediaPlaybackAutoRepeatMode, MediaPlaybackType,
+    },
+    Storage::Streams::{DataReader, DataWriter, IInputStream, InMemoryRandomAccessStream},
+    UI::{
+        Notifications::{ToastNotification, ToastNotificationManager, ToastTemplateType},
+        Shell::FocusSessionManager,
+    },
+    Web::Http::HttpClient,
     Win32::{
-        Foundation::{HWND, LPARAM, LRESULT, WPARAM},
-        System::LibraryLoader::{GetModuleHandleA, GetProcAddress, LoadLibraryA},
+        Foundation::{ERROR_CLASS_ALREADY_EXISTS, ERROR_FILE_NOT_FOUND, GetLastError, GlobalFree, HANDLE, HWND, LPARAM, LRESULT, RECT, WPARAM},
+        Globalization::GetUserDefaultUILanguage,
+        Graphics::Gdi::{
+            BI_RGB, BITMAPINFO, BITMAPINFOHEADER, BeginPaint, COLOR_BTNFACE, DEFAULT_GUI_FONT, DIB_RGB_COLORS, DT_END_ELLIPSIS, DT_NOPREFIX, DT_SINGLELINE,
+            DrawTextW, EndPaint, EnumDisplayMonitors, FillRect, GetMonitorInfoW, GetStockObject, GetSysColorBrush, HDC, HMONITOR, InvalidateRect, MONITORINFO,
+            PAINTSTRUCT, SRCCOPY, SelectObject, SetBkMode, StretchDIBits, TRANSPARENT,
+        },
+        System::{
+            Console::{ATTACH_PARENT_PROCESS, AttachConsole},
+            DataExchange::{CloseClipboard, EmptyClipboard, OpenClipboard, SetClipboardData},
+            LibraryLoader::{GetModuleHandleA, GetProcAddress, LoadLibraryA},
+            Memory::{GMEM_MOVEABLE, GlobalAlloc, GlobalLock, GlobalUnlock},
+            Ole::CF_UNICODETEXT,
+            Registry::{HKEY_CURRENT_USER, REG_SZ, RRF_RT_REG_SZ, RegDeleteKeyValueW, RegGetValueW, RegSetKeyValueW},
+            SystemInformation::{GetLocalTime, OSVERSIONINFOW},
+            SystemServices::SS_ENDELLIPSIS,
+            Threading::{AttachThreadInput, GetCurrentThreadId},
+        },
         UI::{
-            Shell::{NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NOTIFYICONDATAA, Shell_NotifyIconA},
+            Input::KeyboardAndMouse::{INPUT, INPUT_MOUSE, SendInput},
+            Shell::{
+                NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY, NOTIFYICONDATAW, QUNS_BUSY, QUNS_PRESENTATION_MODE,
+                QUNS_RUNNING_D3D_FULL_SCREEN, SHQueryUserNotificationState, Shell_NotifyIconW,
+            },
             WindowsAndMessaging::{
-                AppendMenuA, CS_HREDRAW, CW_USEDEFAULT, CreatePopupMenu, CreateWindowExA, DefWindowProcA, DeleteMenu, DispatchMessageA, GWLP_USERDATA,
-                GetCursorPos, GetMessageA, GetWindowLongPtrA, HMENU, IDC_ARROW, LoadCursorW, LoadIconA, MF_BYCOMMAND, MF_CHECKED, MF_SEPARATOR, MF_STRING,
-                MF_UNCHECKED, MSG, PostQuitMessage, RegisterClassA, SetForegroundWindow, SetWindowLongPtrA, TPM_RIGHTBUTTON, TrackPopupMenu, WINDOW_EX_STYLE,
-                WM_COMMAND, WM_DESTROY, WM_RBUTTONUP, WM_USER, WNDCLASSA, WS_OVERLAPPEDWINDOW,
+                AppendMenuW, BM_GETCHECK, BM_SETCHECK, BS_AUTOCHECKBOX, BS_DEFPUSHBUTTON, BS_PUSHBUTTON, CS_HREDRAW, CW_USEDEFAULT, CreatePopupMenu,
+                CreateWindowExA, CreateWindowExW, DefWindowProcA, DefWindowProcW, DeleteMenu, DestroyWindow, DispatchMessageA, DispatchMessageW,
+                ES_AUTOHSCROLL, ES_AUTOVSCROLL, ES_MULTILINE, ES_NUMBER, ES_WANTRETURN, FindWindowW, GWLP_USERDATA, GetClientRect, GetCursorPos,
+                GetForegroundWindow, GetMessageA, GetMessageW, GetWindowLongPtrA, GetWindowLongPtrW, GetWindowRect, GetWindowTextLengthW, GetWindowTextW,
+                GetWindowThreadProcessId, HMENU, HTCAPTION, IDC_ARROW, IDI_APPLICATION, LB_ADDSTRING, LB_GETSEL, LB_SETSEL, LBS_MULTIPLESEL,
+                LBS_NOINTEGRALHEIGHT, LoadCursorW, LoadIconA, LoadIconW, MF_BYCOMMAND, MF_CHECKED, MF_ENABLED, MF_GRAYED, MF_POPUP, MF_SEPARATOR, MF_STRING,
+                MF_UNCHECKED, MSG, PBT_APMRESUMEAUTOMATIC, PostMessageA, PostQuitMessage, RegisterClassA, RegisterClassW, SW_SHOW, SW_SHOWNOACTIVATE,
+                SendMessageW, SetForegroundWindow, SetTimer, SetWindowLongPtrA, SetWindowLongPtrW, ShowWindow, TPM_RIGHTBUTTON, TrackPopupMenu,
+                TranslateMessage, WINDOW_EX_STYLE, WINDOW_STYLE, WM_COMMAND, WM_DESTROY, WM_EXITSIZEMOVE, WM_LBUTTONUP, WM_NCHITTEST, WM_NULL, WM_PAINT,
+                WM_POWERBROADCAST, WM_RBUTTONUP, WM_SETFONT, WM_TIMER, WM_USER, WNDCLASSA, WNDCLASSW, WS_BORDER, WS_CAPTION, WS_CHILD, WS_EX_NOACTIVATE,
+                WS_EX_TOOLWINDOW, WS_EX_TOPMOST, WS_OVERLAPPEDWINDOW, WS_POPUP, WS_SYSMENU, WS_TABSTOP, WS_VISIBLE, WS_VSCROLL,
             },
         },
     },
-    core::Interface,
+    core::{BOOL, Interface},
 };
-use windows_strings::PCSTR;
+use windows_strings::{HSTRING, PCSTR, PCWSTR};
+
+static RUN_TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+const RUN_TEMP_DIR_ENV: &str = "NOW_PLAYING_RUN_TEMP_DIR";
+
+fn create_run_temp_dir() -> anyhow::Result<tempfile::TempDir> {
+    let run_temp_dir = tempfile::Builder::new()
+        .prefix("now-playing_")
+        .tempdir()
+        .context("Can not create run temp dir")?;
+    let _ = RUN_TEMP_DIR.set(run_temp_dir.path().to_path_buf());
+    Ok(run_temp_dir)
+}
 
 fn create_temp_file_with_contents(prefix: &str, suffix: &str, contents: &[u8]) -> anyhow::Result<PathBuf> {
-    let named_temp_file = tempfile::Builder::new().disable_cleanup(true).prefix(prefix).suffix(suffix).tempfile()?;
+    let temp_dir = RUN_TEMP_DIR.get().cloned().unwrap_or_else(env::temp_dir);
+    let named_temp_file = tempfile::Builder::new()
+        .disable_cleanup(true)
+        .prefix(prefix)
+        .suffix(suffix)
+        .tempfile_in(temp_dir)?;
     let path = named_temp_file.path().to_path_buf();
     let mut file = named_temp_file.into_file();
     file.write_all(contents)?;
     Ok(path)
 }
 
+fn write_file_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().ok_or(anyhow!("Path has no parent"))?;
+    fs::create_dir_all(dir)?;
+    let mut named_temp_file = tempfile::Builder::new().prefix(".now-playing-").tempfile_in(dir)?;
+    named_temp_file.write_all(contents)?;
+    named_temp_file.persist(path)?;
+    Ok(())
+}
+
 fn mime_type_to_extension(mime_type: &str) -> anyhow::Result<String> {
     for bitmap_codec_information in BitmapDecoder::GetDecoderInformationEnumerator()? {
         for codec_mime_type in bitmap_codec_information.MimeTypes()? {
@@ -68,12 +134,133 @@ fn mime_type_to_extension(mime_type: &str) -> anyhow::Result<String> {
     bail!("No extension found")
 }
 
+fn extension_to_mime_type(extension: &str) -> anyhow::Result<String> {
+    for bitmap_codec_information in BitmapDecoder::GetDecoderInformationEnumerator()? {
+        for codec_extension in bitmap_codec_information.FileExtensions()? {
+            if codec_extension.to_string_lossy().eq_ignore_ascii_case(extension) {
+                return Ok(bitmap_codec_information
+                    .MimeTypes()?
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow!("Extension found, but has no mime types"))?
+                    .to_string_lossy());
+            }
+        }
+    }
+    bail!("No mime type found")
+}
+
+/// Formats sources commonly use for thumbnails
+const COMMON_THUMBNAIL_MIME_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp", "image/gif"];
+
+fn command_check_codecs() -> anyhow::Result<()> {
+    println!("Installed decoders:");
+    for bitmap_codec_information in BitmapDecoder::GetDecoderInformationEnumerator().context("Can not enumerate decoders")? {
+        let mime_types = bitmap_codec_information
+            .MimeTypes()?
+            .into_iter()
+            .map(|mime_type| mime_type.to_string_lossy())
+            .join(", ");
+        let extensions = bitmap_codec_information
+            .FileExtensions()?
+            .into_iter()
+            .map(|extension| extension.to_string_lossy())
+            .join(", ");
+        println!("  {}: {mime_types} ({extensions})", bitmap_codec_information.FriendlyName()?);
+    }
+    let mut missing = vec![];
+    println!("Common thumbnail formats:");
+    for mime_type in COMMON_THUMBNAIL_MIME_TYPES {
+        match mime_type_to_extension(mime_type) {
+            Ok(extension) => println!("  {mime_type}: {extension}"),
+            Err(e) => {
+                println!("  {mime_type}: unsupported, {e:#}");
+                missing.push(*mime_type);
+            }
+        }
+    }
+    if !missing.is_empty() {
+        bail!("No decoder for {}, thumbnails in these formats are not shown", missing.join(", "))
+    }
+    Ok(())
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 struct Thumbnail {
     mime_type: String,
     bytes: Box<[u8]>,
 }
 
+impl Thumbnail {
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let extension = path.extension().ok_or(anyhow!("File has no extension"))?.to_string_lossy();
+        let mime_type = extension_to_mime_type(&format!(".{extension}"))?;
+        let bytes = fs::read(path)?.into_boxed_slice();
+        if bytes.is_empty() {
+            bail!("File is empty")
+        }
+        Ok(Self { mime_type, bytes })
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum ToastScenario {
+    #[default]
+    Default,
+    Reminder,
+    Alarm,
+    IncomingCall,
+    /// Windows 11 important notification, shown during Do not disturb for apps the user allowed to
+    Urgent,
+}
+
+impl ToastScenario {
+    fn attribute(self) -> Option<&'static str> {
+        match self {
+            Self::Default => None,
+            Self::Reminder => Some("reminder"),
+            Self::Alarm => Some("alarm"),
+            Self::IncomingCall => Some("incomingCall"),
+            Self::Urgent => Some("urgent"),
+        }
+    }
+}
+
+/// Builds before this reject the `urgent` scenario
+const URGENT_TOAST_MIN_BUILD: u32 = 22546;
+
+fn supports_urgent_toasts() -> bool {
+    static SUPPORTS_URGENT_TOASTS: LazyLock<bool> = LazyLock::new(|| windows_build_number().is_ok_and(|build_number| build_number >= URGENT_TOAST_MIN_BUILD));
+    *SUPPORTS_URGENT_TOASTS
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+enum ToastSound {
+    #[default]
+    Silent,
+    Default,
+    Custom(String),
+}
+
+impl ToastSound {
+    /// Desktop apps can only play the system sounds, anything else makes Windows drop the whole toast
+    fn check(&self) -> anyhow::Result<()> {
+        if let Self::Custom(src) = self
+            && !src.starts_with("ms-winsoundevent:Notification.")
+        {
+            bail!("{src:?} is not a ms-winsoundevent:Notification.* sound")
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum ToastStyle {
+    Compact,
+    #[default]
+    Full,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Toast {
     duration: Duration,
@@ -82,13 +269,80 @@ struct Toast {
     line_2: String,
     line_3: String,
     thumbnail: Option<Thumbnail>,
+    scenario: ToastScenario,
+    sound: ToastSound,
+    force_aumid: Option<String>,
+    launch_uri: Option<String>,
+    replace_visible: bool,
+    monitor: Option<usize>,
+    style: ToastStyle,
+    custom_xml: Option<String>,
+    prune_history: bool,
+    link_uri: Option<String>,
 }
 
-async fn command_send_toast(toast: Toast) -> anyhow::Result<()> {
-    let toast_template = ToastNotificationManager::GetTemplateContent(if toast.thumbnail.is_some() {
-        ToastTemplateType::ToastImageAndText04
-    } else {
-        ToastTemplateType::ToastText04
+const CUSTOM_TOAST_XML_PLACEHOLDERS: &[&str] = &["line_1", "line_2", "line_3", "image"];
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn render_custom_toast_xml(custom_xml: &str, values: [&str; 4]) -> String {
+    CUSTOM_TOAST_XML_PLACEHOLDERS
+        .iter()
+        .zip(values)
+        .fold(custom_xml.to_owned(), |custom_xml, (placeholder, value)| {
+            custom_xml.replace(&format!("{{{placeholder}}}"), &xml_escape(value))
+        })
+}
+
+fn load_custom_toast_xml(custom_toast_xml_path: &Path) -> anyhow::Result<String> {
+    let custom_xml = fs::read_to_string(custom_toast_xml_path).context("Can not read custom toast xml")?;
+    if !custom_xml.contains("{line_1}") {
+        bail!("Custom toast xml does not contain the {{line_1}} placeholder");
+    }
+    let toast_template = XmlDocument::new().context("Can not create xml document")?;
+    toast_template
+        .LoadXml(&render_custom_toast_xml(&custom_xml, ["Title", "Artist", "Album", "file:///thumbnail.png"]).into())
+        .context("Can not parse custom toast xml")?;
+    Ok(custom_xml)
+}
+
+fn build_custom_toast_xml(toast: &Toast, custom_xml: &str) -> anyhow::Result<XmlDocument> {
+    let image_uri = match &toast.thumbnail {
+        Some(thumbnail) if custom_xml.contains("{image}") => {
+            let extension = mime_type_to_extension(&thumbnail.mime_type)?;
+            let thumbnail_path = create_temp_file_with_contents("thumbnail_f", &extension, &thumbnail.bytes).context("Can not create temporary file")?;
+            format!("file:///{}", thumbnail_path.as_os_str().to_string_lossy())
+        }
+        _ => String::new(),
+    };
+    let toast_template = XmlDocument::new().context("Can not create xml document")?;
+    toast_template
+        .LoadXml(&render_custom_toast_xml(custom_xml, [&toast.line_1, &toast.line_2, &toast.line_3, &image_uri]).into())
+        .context("Can not parse custom toast xml")?;
+    Ok(toast_template)
+}
+
+fn build_toast_xml(toast: &Toast) -> anyhow::Result<XmlDocument> {
+    if let Some(custom_xml) = &toast.custom_xml {
+        match build_custom_toast_xml(toast, custom_xml) {
+            Ok(toast_template) => return Ok(toast_template),
+            Err(e) => log(format!("Falling back to the built-in toast template: {e:?}")),
+        }
+    }
+    // Without a third line use the template with two text slots, so there is no blank line at the bottom
+    let toast_template = ToastNotificationManager::GetTemplateContent(match (toast.style, toast.thumbnail.is_some(), toast.line_3.is_empty()) {
+        (ToastStyle::Compact, true, _) => ToastTemplateType::ToastImageAndText01,
+        (ToastStyle::Compact, false, _) => ToastTemplateType::ToastText01,
+        (ToastStyle::Full, true, true) => ToastTemplateType::ToastImageAndText02,
+        (ToastStyle::Full, false, true) => ToastTemplateType::ToastText02,
+        (ToastStyle::Full, true, false) => ToastTemplateType::ToastImageAndText04,
+        (ToastStyle::Full, false, false) => ToastTemplateType::ToastText04,
     })
     .context("Can not get template content")?;
     let toast_element = toast_template
@@ -122,7 +376,7 @@ async fn command_send_toast(toast: Toast) -> anyhow::Result<()> {
                 .context("Can not append child")?;
         }
     }
-    if let Some(thumbnail) = toast.thumbnail
+    if let Some(thumbnail) = &toast.thumbnail
         && let Ok(extension) = mime_type_to_extension(&thumbnail.mime_type)
     {
         let thumbnail_path = create_temp_file_with_contents("thumbnail_f", &extension, &thumbnail.bytes).context("Can not create temporary file")?;
@@ -145,28 +399,193 @@ async fn command_send_toast(toast: Toast) -> anyhow::Result<()> {
             }
         }
     }
-    let audio_element = toast_template.CreateElement(&"audio".into()).context("Can not create element <audio>")?;
-    audio_element
-        .SetAttribute(&"silent".into(), &"true".into())
-        .context("Can not set attribute `silent`")?;
-    toast_element.AppendChild(&audio_element).context("Can not append child")?;
-    let toast_notifier = ToastNotificationManager::CreateToastNotifierWithId(&toast.source_app_user_mode_id.into()).context("Can not creat toast notifier")?;
+    if let Some(launch_uri) = &toast.launch_uri {
+        toast_element
+            .SetAttribute(&"activationType".into(), &"protocol".into())
+            .context("Can not set attribute `activationType`")?;
+        toast_element
+            .SetAttribute(&"launch".into(), &launch_uri.as_str().into())
+            .context("Can not set attribute `launch`")?;
+    }
+    let actions_element = toast_template.CreateElement(&"actions".into()).context("Can not create element <actions>")?;
+    if let Some(link_uri) = &toast.link_uri {
+        let action_element = toast_template.CreateElement(&"action".into()).context("Can not create element <action>")?;
+        action_element
+            .SetAttribute(&"activationType".into(), &"protocol".into())
+            .context("Can not set attribute `activationType`")?;
+        action_element
+            .SetAttribute(&"arguments".into(), &link_uri.as_str().into())
+            .context("Can not set attribute `arguments`")?;
+        action_element
+            .SetAttribute(&"content".into(), &"Open".into())
+            .context("Can not set attribute `content`")?;
+        actions_element.AppendChild(&action_element).context("Can not append child")?;
+    }
+    if let Some(scenario) = toast.scenario.attribute() {
+        toast_element
+            .SetAttribute(&"scenario".into(), &scenario.into())
+            .context("Can not set attribute `scenario`")?;
+        // Windows ignores the scenario of a toast without buttons, so give it a system dismiss button
+        let action_element = toast_template.CreateElement(&"action".into()).context("Can not create element <action>")?;
+        action_element
+            .SetAttribute(&"activationType".into(), &"system".into())
+            .context("Can not set attribute `activationType`")?;
+        action_element
+            .SetAttribute(&"arguments".into(), &"dismiss".into())
+            .context("Can not set attribute `arguments`")?;
+        action_element
+            .SetAttribute(&"content".into(), &"".into())
+            .context("Can not set attribute `content`")?;
+        actions_element.AppendChild(&action_element).context("Can not append child")?;
+    }
+    if actions_element.HasChildNodes().context("Can not check element <actions>")? {
+        toast_element.AppendChild(&actions_element).context("Can not append child")?;
+    }
+    match &toast.sound {
+        ToastSound::Silent => {
+            let audio_element = toast_template.CreateElement(&"audio".into()).context("Can not create element <audio>")?;
+            audio_element
+                .SetAttribute(&"silent".into(), &"true".into())
+                .context("Can not set attribute `silent`")?;
+            toast_element.AppendChild(&audio_element).context("Can not append child")?;
+        }
+        ToastSound::Default => (),
+        ToastSound::Custom(src) => {
+            let audio_element = toast_template.CreateElement(&"audio".into()).context("Can not create element <audio>")?;
+            audio_element
+                .SetAttribute(&"src".into(), &src.as_str().into())
+                .context("Can not set attribute `src`")?;
+            // Looping sounds are only accepted for the scenarios that keep the toast on screen until dismissed
+            if src.contains(".Looping.") && matches!(toast.scenario, ToastScenario::Alarm | ToastScenario::IncomingCall) {
+                audio_element
+                    .SetAttribute(&"loop".into(), &"true".into())
+                    .context("Can not set attribute `loop`")?;
+            }
+            toast_element.AppendChild(&audio_element).context("Can not append child")?;
+        }
+    }
+    Ok(toast_template)
+}
+
+const TOAST_GROUP: &str = "now-playing";
+// Windows keeps a limited number of toasts per app in Action Center
+const TOAST_HISTORY_SOFT_LIMIT: usize = 40;
+
+fn prune_toast_history(aumid: &str, keep: usize) -> anyhow::Result<()> {
+    let toast_notification_history = ToastNotificationManager::History().context("Can not get notification history")?;
+    let aumid = HSTRING::from(aumid);
+    let toast_group = HSTRING::from(TOAST_GROUP);
+    let tags = toast_notification_history
+        .GetHistoryWithId(&aumid)
+        .context("Can not get notification history")?
+        .into_iter()
+        .filter(|toast_notification| toast_notification.Group().is_ok_and(|group| group == toast_group))
+        .filter_map(|toast_notification| toast_notification.Tag().ok())
+        .collect::<Vec<_>>();
+    // The history is ordered from oldest to newest
+    for tag in &tags[..tags.len().saturating_sub(keep)] {
+        toast_notification_history
+            .RemoveGroupedTagWithId(tag, &toast_group, &aumid)
+            .context("Can not remove notification")?;
+    }
+    Ok(())
+}
+
+const TOAST_SHOW_ATTEMPTS: u32 = 4;
+const TOAST_SHOW_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+async fn command_send_toast(toast: Toast, print_toast_xml: bool) -> anyhow::Result<()> {
+    let toast_template = build_toast_xml(&toast)?;
+    if print_toast_xml {
+        attach_console();
+        println!("{}", toast_template.GetXml().context("Can not get toast xml")?);
+        return Ok(());
+    }
+    if let Some(monitor) = toast.monitor {
+        return tokio::task::spawn_blocking(move || show_popup(&toast, monitor)).await?;
+    }
+    let aumid = toast.force_aumid.clone().unwrap_or_else(|| toast.source_app_user_mode_id.clone());
+    let toast_notifier = ToastNotificationManager::CreateToastNotifierWithId(&aumid.as_str().into()).context("Can not creat toast notifier")?;
     let toast_notification = ToastNotification::CreateToastNotification(&toast_template).context("Can not creat toast notification")?;
-    toast_notifier.Show(&toast_notification).context("Can not show notification")?;
-    tokio::time::sleep(toast.duration).await;
-    toast_notifier.Hide(&toast_notification).context("Can not hide notification")?;
+    let mut hasher = std::hash::DefaultHasher::new();
+    std::hash::Hash::hash(&toast.source_app_user_mode_id, &mut hasher);
+    if !toast.replace_visible {
+        // A toast with the same tag and group replaces the previous one, so only reuse the tag when that is wanted
+        std::hash::Hash::hash(&SystemTime::now(), &mut hasher);
+    }
+    // Tags are limited to 64 characters, so use a hash
+    toast_notification
+        .SetTag(&format!("{:016x}", std::hash::Hasher::finish(&hasher)).into())
+        .context("Can not set toast tag")?;
+    toast_notification.SetGroup(&TOAST_GROUP.into()).context("Can not set toast group")?;
+    if toast.prune_history
+        && let Err(e) = prune_toast_history(&aumid, TOAST_HISTORY_SOFT_LIMIT)
+    {
+        log(format!("Can not prune notification history: {e:?}"));
+    }
+    // The notification platform can be busy for a moment, e.g. right after login
+    let mut backoff = TOAST_SHOW_RETRY_BACKOFF;
+    for attempt in 1..=TOAST_SHOW_ATTEMPTS {
+        match toast_notifier.Show(&toast_notification) {
+            Ok(()) => break,
+            Err(e) if attempt == TOAST_SHOW_ATTEMPTS => {
+                log(format!("Can not show notification after {TOAST_SHOW_ATTEMPTS} attempts: {e:?}"));
+                return Err(e).context("Can not show notification");
+            }
+            Err(e) => {
+                // A full Action Center makes `Show` fail until older toasts are gone, so make room before trying again
+                if toast.prune_history && attempt == 1 {
+                    log(format!("Can not show notification, pruning our older ones: {e:?}"));
+                    if let Err(e) = prune_toast_history(&aumid, 0) {
+                        log(format!("Can not prune notification history: {e:?}"));
+                    }
+                }
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+    // Other scenarios are meant to stay on screen until the user dismisses them, an urgent toast is a normal one that gets through
+    if matches!(toast.scenario, ToastScenario::Default | ToastScenario::Urgent) {
+        tokio::time::sleep(toast.duration).await;
+        toast_notifier.Hide(&toast_notification).context("Can not hide notification")?;
+    }
     Ok(())
 }
 
 async fn send_toast(toast: Toast) -> anyhow::Result<()> {
     let toast_json = serde_json::to_string(&toast)?;
     let toast_json_path = create_temp_file_with_contents("toast_json_", ".json", toast_json.as_bytes())?;
-    let mut child = std::process::Command::new(env::current_exe()?).arg("send-toast").arg(toast_json_path).spawn()?;
+    let mut command = std::process::Command::new(env::current_exe()?);
+    command.arg("send-toast").arg(toast_json_path);
+    if let Some(run_temp_dir) = RUN_TEMP_DIR.get() {
+        command.env(RUN_TEMP_DIR_ENV, run_temp_dir);
+    }
+    let mut child = command.spawn()?;
     tokio::task::spawn_blocking(move || child.wait()).await??;
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+enum PlaybackType {
+    Unknown,
+    Music,
+    Video,
+    Image,
+}
+
+impl From<MediaPlaybackType> for PlaybackType {
+    fn from(media_playback_type: MediaPlaybackType) -> Self {
+        match media_playback_type {
+            MediaPlaybackType::Music => Self::Music,
+            MediaPlaybackType::Video => Self::Video,
+            MediaPlaybackType::Image => Self::Image,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 struct SessionInfo {
     source_app_user_mode_id: String,
     title: String,
@@ -174,6 +593,10 @@ struct SessionInfo {
     artist: String,
     album_title: String,
     thumbnail: Option<Thumbnail>,
+    playback_status: GlobalSystemMediaTransportControlsSessionPlaybackStatus,
+    playback_type: PlaybackType,
+    duration: Option<Duration>,
+    timeline_position: Option<(Duration, SystemTime)>,
 }
 
 impl PartialEq for SessionInfo {
@@ -188,6 +611,64 @@ impl PartialEq for SessionInfo {
 
 impl Eq for SessionInfo {}
 
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum NameOrder {
+    #[default]
+    SongFirst,
+    ArtistFirst,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum LeftClickAction {
+    #[default]
+    Nothing,
+    ShowCurrentTrack,
+    TogglePauseAll,
+    OpenSettings,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum DedupKey {
+    Title,
+    TitleArtist,
+    #[default]
+    Full,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum NotifyGranularity {
+    #[default]
+    Track,
+    Album,
+    Artist,
+}
+
+impl NotifyGranularity {
+    fn key(self, session_info: &SessionInfo) -> Option<&str> {
+        match self {
+            Self::Track => None,
+            Self::Album => Some(&session_info.album_title),
+            Self::Artist => Some(&session_info.artist),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+enum StartupMode {
+    Silent,
+    CurrentOnly,
+    #[default]
+    All,
+}
+
+fn is_same_track(dedup_key: DedupKey, a: &SessionInfo, b: &SessionInfo) -> bool {
+    match dedup_key {
+        DedupKey::Title => a.source_app_user_mode_id == b.source_app_user_mode_id && a.title == b.title,
+        DedupKey::TitleArtist => a.source_app_user_mode_id == b.source_app_user_mode_id && a.title == b.title && a.artist == b.artist,
+        DedupKey::Full => a == b,
+    }
+}
+
 async fn get_thumbnail(
     global_system_media_transport_controls_session_media_properties: &GlobalSystemMediaTransportControlsSessionMediaProperties,
 ) -> anyhow::Result<Thumbnail> {
@@ -198,15 +679,223 @@ async fn get_thumbnail(
             .await?;
     let mime_type = i_random_access_stream_with_content_type.ContentType()?.to_string_lossy();
     let size = i_random_access_stream_with_content_type.Size()? as usize;
-    let i_input_stream = i_random_access_stream_with_content_type.GetInputStreamAt(0)?;
-    let data_reader = DataReader::CreateDataReader(&i_input_stream)?;
-    data_reader.LoadAsync(size as _)?.await?;
-    let mut bytes = vec![0; size].into_boxed_slice();
-    data_reader.ReadBytes(&mut bytes)?;
+    // Some apps transiently report an empty thumbnail, which would render as a broken picture
+    if size == 0 {
+        bail!("Thumbnail is empty")
+    }
+    let bytes = read_stream_exact(&i_random_access_stream_with_content_type.GetInputStreamAt(0)?, size)
+        .await
+        .context("Can not read thumbnail")?;
     Ok(Thumbnail { mime_type, bytes })
 }
 
-async fn get_session_info(global_system_media_transport_controls_session: &GlobalSystemMediaTransportControlsSession) -> anyhow::Result<SessionInfo> {
+async fn read_stream_exact(i_input_stream: &IInputStream, size: usize) -> anyhow::Result<Box<[u8]>> {
+    let data_reader = DataReader::CreateDataReader(i_input_stream)?;
+    let loaded = data_reader.LoadAsync(size as _)?.await? as usize;
+    let unconsumed = data_reader.UnconsumedBufferLength()? as usize;
+    if loaded < size || unconsumed < size {
+        bail!("Stream is truncated, loaded {} of {size} bytes", loaded.min(unconsumed))
+    }
+    let mut bytes = vec![0; size].into_boxed_slice();
+    data_reader.ReadBytes(&mut bytes).context("Can not read bytes")?;
+    Ok(bytes)
+}
+
+const PNG_MIME_TYPE: &str = "image/png";
+
+fn is_png_mime_type(mime_type: &str) -> bool {
+    mime_type.trim().eq_ignore_ascii_case(PNG_MIME_TYPE)
+}
+
+async fn create_thumbnail_decoder(thumbnail: &Thumbnail) -> anyhow::Result<BitmapDecoder> {
+    let input_stream = InMemoryRandomAccessStream::new()?;
+    let data_writer = DataWriter::CreateDataWriter(&input_stream)?;
+    data_writer.WriteBytes(&thumbnail.bytes)?;
+    data_writer.StoreAsync()?.await?;
+    data_writer.DetachStream()?;
+    input_stream.Seek(0)?;
+    BitmapDecoder::CreateAsync(&input_stream)?.await.context("Can not decode thumbnail")
+}
+
+async fn transcode_thumbnail_to_png(thumbnail: &Thumbnail) -> anyhow::Result<Thumbnail> {
+    let bitmap_decoder = create_thumbnail_decoder(thumbnail).await?;
+    let software_bitmap = bitmap_decoder.GetSoftwareBitmapAsync()?.await.context("Can not decode thumbnail")?;
+    let output_stream = InMemoryRandomAccessStream::new()?;
+    let bitmap_encoder = BitmapEncoder::CreateAsync(BitmapEncoder::PngEncoderId()?, &output_stream)?
+        .await
+        .context("Can not create png encoder")?;
+    bitmap_encoder.SetSoftwareBitmap(&software_bitmap)?;
+    bitmap_encoder.FlushAsync()?.await.context("Can not encode thumbnail")?;
+    let size = output_stream.Size()? as usize;
+    if size == 0 {
+        bail!("Encoded thumbnail is empty")
+    }
+    let bytes = read_stream_exact(&output_stream.GetInputStreamAt(0)?, size)
+        .await
+        .context("Can not read encoded thumbnail")?;
+    Ok(Thumbnail {
+        mime_type: PNG_MIME_TYPE.to_owned(),
+        bytes,
+    })
+}
+
+// The toast renderer draws some formats poorly, e.g. losing transparency
+async fn force_thumbnail_png(thumbnail: Thumbnail) -> Thumbnail {
+    if is_png_mime_type(&thumbnail.mime_type) {
+        return Thumbnail {
+            mime_type: PNG_MIME_TYPE.to_owned(),
+            ..thumbnail
+        };
+    }
+    match transcode_thumbnail_to_png(&thumbnail).await {
+        Ok(png_thumbnail) => png_thumbnail,
+        Err(e) => {
+            log_rate_limited(
+                "force_thumbnail_png",
+                format!("Can not convert {} thumbnail to png: {e:?}", thumbnail.mime_type),
+            );
+            thumbnail
+        }
+    }
+}
+
+const MINI_PLAYER_ART_SIZE: u32 = 64;
+
+/// Thumbnail decoded to top-down premultiplied BGRA rows, ready for `StretchDIBits`
+#[derive(Debug)]
+struct MiniPlayerArt {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+async fn update_mini_player_art(state: &RwLock<State>, thumbnail: Option<&Thumbnail>) {
+    let mini_player_art = match thumbnail {
+        Some(thumbnail) if state.read().unwrap().mini_player_open => decode_mini_player_art(thumbnail)
+            .await
+            .inspect_err(|e| log_rate_limited("mini_player_art", format!("Can not decode mini player art: {e:?}")))
+            .ok(),
+        _ => None,
+    };
+    let mut state = state.write().unwrap();
+    state.mini_player_art = mini_player_art;
+    state.mini_player_revision += 1;
+}
+
+async fn decode_mini_player_art(thumbnail: &Thumbnail) -> anyhow::Result<MiniPlayerArt> {
+    let bitmap_decoder = create_thumbnail_decoder(thumbnail).await?;
+    let (pixel_width, pixel_height) = (bitmap_decoder.OrientedPixelWidth()?, bitmap_decoder.OrientedPixelHeight()?);
+    if pixel_width == 0 || pixel_height == 0 {
+        bail!("Thumbnail has no pixels")
+    }
+    let scale = MINI_PLAYER_ART_SIZE as f64 / pixel_width.max(pixel_height) as f64;
+    let width = ((pixel_width as f64 * scale).round() as u32).max(1);
+    let height = ((pixel_height as f64 * scale).round() as u32).max(1);
+    let bitmap_transform = BitmapTransform::new()?;
+    bitmap_transform.SetScaledWidth(width)?;
+    bitmap_transform.SetScaledHeight(height)?;
+    bitmap_transform.SetInterpolationMode(BitmapInterpolationMode::Fant)?;
+    let pixel_data_provider = bitmap_decoder
+        .GetPixelDataTransformedAsync(
+            BitmapPixelFormat::Bgra8,
+            BitmapAlphaMode::Premultiplied,
+            &bitmap_transform,
+            ExifOrientationMode::RespectExifOrientation,
+            ColorManagementMode::DoNotColorManage,
+        )?
+        .await
+        .context("Can not decode thumbnail pixels")?;
+    Ok(MiniPlayerArt {
+        width,
+        height,
+        pixels: pixel_data_provider.DetachPixelData()?.to_vec(),
+    })
+}
+
+const LOCAL_ART_FILE_NAMES: &[&str] = &["cover.jpg", "folder.jpg", "front.jpg", "albumart.jpg", "cover.png", "folder.png", "front.png"];
+const LOCAL_ART_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%'
+            && let Some(hex) = tail.get(..2)
+            && let Ok(decoded) = u8::from_str_radix(&String::from_utf8_lossy(hex), 16)
+        {
+            bytes.push(decoded);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// GSMTC reports no track ID, so the link can only search for the track in the source
+fn track_link(session_info: &SessionInfo) -> Option<String> {
+    if session_info.title.trim().is_empty() {
+        return None;
+    }
+    let query = percent_encode(&[&session_info.title, &session_info.artist].into_iter().filter(|s| !s.is_empty()).join(" "));
+    let source_app_user_mode_id = session_info.source_app_user_mode_id.to_lowercase();
+    if source_app_user_mode_id == "spotify.exe" || source_app_user_mode_id.starts_with("spotifyab.spotifymusic_") {
+        Some(format!("spotify:search:{query}"))
+    } else if source_app_user_mode_id.starts_with("appleinc.applemusicwin_") {
+        Some(format!("https://music.apple.com/search?term={query}"))
+    } else {
+        None
+    }
+}
+
+// Some local players without a thumbnail stream leak the path of the playing file into one of the text fields
+fn find_local_art(session_info: &SessionInfo) -> Option<Thumbnail> {
+    for field in [&session_info.title, &session_info.subtitle, &session_info.album_title, &session_info.artist] {
+        let field = field.trim();
+        let file_path = match field.strip_prefix("file:///") {
+            Some(uri_path) => PathBuf::from(percent_decode(uri_path)),
+            None => PathBuf::from(field),
+        };
+        if !file_path.is_absolute() || !file_path.is_file() {
+            continue;
+        }
+        let dir = file_path.parent()?;
+        for local_art_file_name in LOCAL_ART_FILE_NAMES {
+            let local_art_path = dir.join(local_art_file_name);
+            if let Ok(metadata) = fs::metadata(&local_art_path)
+                && metadata.is_file()
+                && metadata.len() > 0
+                && metadata.len() <= LOCAL_ART_MAX_SIZE
+                && let Ok(bytes) = fs::read(&local_art_path)
+            {
+                let mime_type = if local_art_file_name.ends_with(".png") { "image/png" } else { "image/jpeg" };
+                return Some(Thumbnail {
+                    mime_type: mime_type.to_owned(),
+                    bytes: bytes.into_boxed_slice(),
+                });
+            }
+        }
+    }
+    None
+}
+
+async fn get_session_info(
+    global_system_media_transport_controls_session: &GlobalSystemMediaTransportControlsSession,
+    fetch_thumbnail: bool,
+) -> anyhow::Result<SessionInfo> {
     let source_app_user_mode_id = global_system_media_transport_controls_session
         .SourceAppUserModelId()
         .context("Can not get source app user model id")?
@@ -232,7 +921,35 @@ async fn get_session_info(global_system_media_transport_controls_session: &Globa
         .AlbumTitle()
         .context("Can not get album title")?
         .to_string_lossy();
-    let thumbnail = get_thumbnail(&global_system_media_transport_controls_session_media_properties).await.ok();
+    let thumbnail = if fetch_thumbnail {
+        get_thumbnail(&global_system_media_transport_controls_session_media_properties).await.ok()
+    } else {
+        None
+    };
+    let playback_info = global_system_media_transport_controls_session
+        .GetPlaybackInfo()
+        .context("Can not get playback info")?;
+    let playback_status = playback_info.PlaybackStatus().context("Can not get playback status")?;
+    let playback_type = playback_info
+        .PlaybackType()
+        .and_then(|playback_type| playback_type.Value())
+        .map(PlaybackType::from)
+        .unwrap_or(PlaybackType::Unknown);
+    let timeline_properties = global_system_media_transport_controls_session.GetTimelineProperties().ok();
+    let duration = timeline_properties
+        .as_ref()
+        .and_then(|timeline_properties| timeline_properties.EndTime().ok())
+        .filter(|end_time| end_time.Duration > 0)
+        .map(time_span_to_duration);
+    let timeline_position = timeline_properties.and_then(|timeline_properties| {
+        let position = timeline_properties.Position().ok()?;
+        let end_time = timeline_properties.EndTime().ok()?;
+        let last_updated_time = timeline_properties.LastUpdatedTime().ok()?;
+        if position.Duration <= 0 && end_time.Duration <= 0 {
+            return None;
+        }
+        Some((time_span_to_duration(position), date_time_to_system_time(last_updated_time)))
+    });
     Ok(SessionInfo {
         source_app_user_mode_id,
         title,
@@ -240,38 +957,262 @@ async fn get_session_info(global_system_media_transport_controls_session: &Globa
         artist,
         album_title,
         thumbnail,
+        playback_status,
+        playback_type,
+        duration,
+        timeline_position,
+    })
+}
+
+const RFC3339_TIMESTAMP_FORMAT: &str = "rfc3339";
+
+/// Date of a day count since the Unix epoch, from Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u32;
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Day count since the Unix epoch of a date, from Howard Hinnant's `days_from_civil`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn parse_date(date: &str) -> anyhow::Result<i64> {
+    let Some((year, month, day)) = date.split('-').collect_tuple() else {
+        bail!("`{date}` is not a YYYY-MM-DD date")
+    };
+    let (year, month, day) = (
+        year.parse::<i64>().with_context(|| format!("`{year}` is not a year"))?,
+        month.parse::<u32>().with_context(|| format!("`{month}` is not a month"))?,
+        day.parse::<u32>().with_context(|| format!("`{day}` is not a day"))?,
+    );
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        bail!("`{date}` is not a valid date")
+    }
+    Ok(days)
+}
+
+fn format_timestamp(timestamp_format: &str) -> anyhow::Result<String> {
+    if timestamp_format == RFC3339_TIMESTAMP_FORMAT {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = since_epoch.as_secs() as i64;
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+        let seconds_of_day = seconds.rem_euclid(86_400);
+        return Ok(format!(
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60,
+            since_epoch.subsec_millis()
+        ));
+    }
+    let local_time = unsafe { GetLocalTime() };
+    let mut formatted = String::new();
+    let mut chars = timestamp_format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => formatted += &format!("{:04}", local_time.wYear),
+            Some('m') => formatted += &format!("{:02}", local_time.wMonth),
+            Some('d') => formatted += &format!("{:02}", local_time.wDay),
+            Some('H') => formatted += &format!("{:02}", local_time.wHour),
+            Some('M') => formatted += &format!("{:02}", local_time.wMinute),
+            Some('S') => formatted += &format!("{:02}", local_time.wSecond),
+            Some('%') => formatted.push('%'),
+            Some(c) => bail!("Unknown timestamp specifier %{c}"),
+            None => bail!("Timestamp format ends with a lone %"),
+        }
+    }
+    Ok(formatted)
+}
+
+static LOG_TIMESTAMP_FORMAT: RwLock<String> = RwLock::new(String::new());
+
+fn set_log_timestamp_format(timestamp_format: &str) {
+    *LOG_TIMESTAMP_FORMAT.write().unwrap() = timestamp_format.to_owned();
+}
+
+fn log(message: impl Display) {
+    let timestamp_format = LOG_TIMESTAMP_FORMAT.read().unwrap().clone();
+    let timestamp = format_timestamp(if timestamp_format.is_empty() {
+        RFC3339_TIMESTAMP_FORMAT
+    } else {
+        &timestamp_format
     })
+    .unwrap_or_default();
+    eprintln!("{timestamp} {message}");
+}
+
+const LOG_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+struct RateLimitedLogEntry {
+    window_start: Instant,
+    suppressed: u32,
+}
+
+static RATE_LIMITED_LOG_ENTRIES: LazyLock<Mutex<HashMap<String, RateLimitedLogEntry>>> = LazyLock::new(Default::default);
+
+fn log_rate_limited(key: &str, message: impl Display) {
+    let mut rate_limited_log_entries = RATE_LIMITED_LOG_ENTRIES.lock().unwrap();
+    let now = Instant::now();
+    match rate_limited_log_entries.get_mut(key) {
+        Some(rate_limited_log_entry) if now.duration_since(rate_limited_log_entry.window_start) < LOG_RATE_LIMIT_WINDOW => {
+            rate_limited_log_entry.suppressed += 1;
+        }
+        Some(rate_limited_log_entry) => {
+            if rate_limited_log_entry.suppressed > 0 {
+                log(rate_limited_log_summary(key, rate_limited_log_entry.suppressed));
+            }
+            rate_limited_log_entry.window_start = now;
+            rate_limited_log_entry.suppressed = 0;
+            log(message);
+        }
+        None => {
+            rate_limited_log_entries.insert(
+                key.to_string(),
+                RateLimitedLogEntry {
+                    window_start: now,
+                    suppressed: 0,
+                },
+            );
+            log(message);
+        }
+    }
+}
+
+fn rate_limited_log_summary(key: &str, suppressed: u32) -> String {
+    format!("{key}: failed {suppressed} more times in the last minute")
+}
+
+fn take_rate_limited_log_summaries(rate_limited_log_entries: &mut HashMap<String, RateLimitedLogEntry>, now: Instant) -> Vec<String> {
+    let mut summaries = vec![];
+    rate_limited_log_entries.retain(|key, rate_limited_log_entry| {
+        if now.duration_since(rate_limited_log_entry.window_start) < LOG_RATE_LIMIT_WINDOW {
+            return true;
+        }
+        if rate_limited_log_entry.suppressed > 0 {
+            summaries.push(rate_limited_log_summary(key, rate_limited_log_entry.suppressed));
+        }
+        false
+    });
+    summaries
+}
+
+async fn flush_rate_limited_logs() {
+    loop {
+        tokio::time::sleep(LOG_RATE_LIMIT_WINDOW).await;
+        let summaries = take_rate_limited_log_summaries(&mut RATE_LIMITED_LOG_ENTRIES.lock().unwrap(), Instant::now());
+        for summary in summaries {
+            log(summary);
+        }
+    }
+}
+
+const MIN_SESSION_RETRY_BACKOFF: Duration = Duration::from_millis(5);
+
+struct SessionSubscription {
+    global_system_media_transport_controls_session: GlobalSystemMediaTransportControlsSession,
+    media_properties_changed_token: i64,
+    playback_info_changed_token: i64,
+}
+
+impl Drop for SessionSubscription {
+    fn drop(&mut self) {
+        let _ = self
+            .global_system_media_transport_controls_session
+            .RemoveMediaPropertiesChanged(self.media_properties_changed_token);
+        let _ = self
+            .global_system_media_transport_controls_session
+            .RemovePlaybackInfoChanged(self.playback_info_changed_token);
+    }
 }
 
-async fn get_session_infos(event_tx: UnboundedSender<Event>) -> anyhow::Result<Vec<SessionInfo>> {
+async fn get_session_infos(
+    global_system_media_transport_controls_session_manager: &GlobalSystemMediaTransportControlsSessionManager,
+    event_tx: UnboundedSender<Event>,
+    session_subscriptions: &mut Vec<SessionSubscription>,
+    retry_attempts: u32,
+    retry_delay: Duration,
+    fetch_thumbnails: bool,
+) -> anyhow::Result<Vec<SessionInfo>> {
     let mut session_infos = vec![];
-    let global_system_media_transport_controls_session_manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-        .context("Can not get global system media transport controls session manager")?
-        .await
-        .context("Can not get global system media transport controls session manager")?;
-    for global_system_media_transport_controls_session in global_system_media_transport_controls_session_manager
+    let global_system_media_transport_controls_sessions = global_system_media_transport_controls_session_manager
         .GetSessions()
         .context("Can not get sessions")?
-    {
-        global_system_media_transport_controls_session.MediaPropertiesChanged(&TypedEventHandler::new({
-            let event_tx = event_tx.clone();
-            move |_, _| {
-                event_tx
-                    .send(Event::Update)
-                    .map_err(|e| windows_result::Error::from(std::io::Error::new(ErrorKind::BrokenPipe, e)))?;
-                Ok(())
-            }
-        }))?;
-        tokio::time::sleep(Duration::new(0, 50_000_000)).await;
-        for _ in 0..20 {
-            let session_info_result = get_session_info(&global_system_media_transport_controls_session).await;
+        .into_iter()
+        .collect::<Vec<_>>();
+    session_subscriptions.retain(|session_subscription| {
+        global_system_media_transport_controls_sessions.contains(&session_subscription.global_system_media_transport_controls_session)
+    });
+    for global_system_media_transport_controls_session in global_system_media_transport_controls_sessions {
+        // Every update enumerates the sessions again, so only subscribe to the ones not seen before
+        if !session_subscriptions
+            .iter()
+            .any(|session_subscription| session_subscription.global_system_media_transport_controls_session == global_system_media_transport_controls_session)
+        {
+            let media_properties_changed_token = global_system_media_transport_controls_session.MediaPropertiesChanged(&TypedEventHandler::new({
+                let event_tx = event_tx.clone();
+                move |_, _| {
+                    event_tx
+                        .send(Event::Update)
+                        .map_err(|e| windows_result::Error::from(std::io::Error::new(ErrorKind::BrokenPipe, e)))?;
+                    Ok(())
+                }
+            }))?;
+            let playback_info_changed_token = global_system_media_transport_controls_session.PlaybackInfoChanged(&TypedEventHandler::new({
+                let event_tx = event_tx.clone();
+                move |_, _| {
+                    event_tx
+                        .send(Event::Update)
+                        .map_err(|e| windows_result::Error::from(std::io::Error::new(ErrorKind::BrokenPipe, e)))?;
+                    Ok(())
+                }
+            }))?;
+            session_subscriptions.push(SessionSubscription {
+                global_system_media_transport_controls_session: global_system_media_transport_controls_session.clone(),
+                media_properties_changed_token,
+                playback_info_changed_token,
+            });
+        }
+        // Most sessions can be read right away
+        let mut backoff = MIN_SESSION_RETRY_BACKOFF.min(retry_delay);
+        for attempt in 1..=retry_attempts {
+            let session_info_result = get_session_info(&global_system_media_transport_controls_session, fetch_thumbnails).await;
             match session_info_result {
                 Ok(session_info) => {
                     session_infos.push(session_info);
                     break;
                 }
+                Err(e) if attempt == retry_attempts => {
+                    let source_app_user_mode_id = global_system_media_transport_controls_session
+                        .SourceAppUserModelId()
+                        .map(|source_app_user_mode_id| source_app_user_mode_id.to_string())
+                        .unwrap_or_default();
+                    log_rate_limited(
+                        &format!("{source_app_user_mode_id}: {e:#}"),
+                        format!("Can not get session info of {source_app_user_mode_id} after {retry_attempts} attempts: {e:#}"),
+                    );
+                }
                 Err(_) => {
-                    tokio::time::sleep(Duration::new(0, 50_000_000)).await;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(retry_delay);
                 }
             }
         }
@@ -279,21 +1220,1977 @@ async fn get_session_infos(event_tx: UnboundedSender<Event>) -> anyhow::Result<V
     Ok(session_infos)
 }
 
-#[derive(PartialEq, Eq, Debug)]
-enum Event {
-    Update,
-    ConfigChanged,
+fn playback_status_name(playback_status: GlobalSystemMediaTransportControlsSessionPlaybackStatus) -> &'static str {
+    match playback_status {
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus::Closed => "Closed",
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus::Opened => "Opened",
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus::Changing => "Changing",
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus::Stopped => "Stopped",
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing => "Playing",
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus::Paused => "Paused",
+        _ => "Unknown",
+    }
+}
+
+fn playback_type_name(playback_type: MediaPlaybackType) -> &'static str {
+    match playback_type {
+        MediaPlaybackType::Music => "Music",
+        MediaPlaybackType::Video => "Video",
+        MediaPlaybackType::Image => "Image",
+        _ => "Unknown",
+    }
+}
+
+fn auto_repeat_mode_name(auto_repeat_mode: MediaPlaybackAutoRepeatMode) -> &'static str {
+    match auto_repeat_mode {
+        MediaPlaybackAutoRepeatMode::None => "None",
+        MediaPlaybackAutoRepeatMode::Track => "Track",
+        MediaPlaybackAutoRepeatMode::List => "List",
+        _ => "Unknown",
+    }
+}
+
+fn format_time_span(time_span: TimeSpan) -> String {
+    format!("{:.1}s", time_span.Duration as f64 / 10_000_000.0)
+}
+
+/// `DateTime` counts 100ns ticks since 1601-01-01
+const UNIX_EPOCH_TICKS: i64 = 116_444_736_000_000_000;
+
+fn time_span_to_duration(time_span: TimeSpan) -> Duration {
+    Duration::from_nanos(time_span.Duration.max(0) as u64 * 100)
+}
+
+fn date_time_to_system_time(date_time: DateTime) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos((date_time.UniversalTime - UNIX_EPOCH_TICKS).max(0) as u64 * 100)
+}
+
+fn format_date_time(date_time: DateTime) -> String {
+    let now_ticks = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as i64 / 100 + UNIX_EPOCH_TICKS;
+    format!("{:.1}s ago", (now_ticks - date_time.UniversalTime) as f64 / 10_000_000.0)
+}
+
+fn diagnostic<T, R, F>(result: windows_result::Result<T>, format: F) -> String
+where
+    R: Display,
+    F: FnOnce(T) -> R,
+{
+    match result {
+        Ok(value) => format(value).to_string(),
+        Err(e) => format!("<unavailable: {}>", e.message()),
+    }
+}
+
+async fn print_session_diagnostics(global_system_media_transport_controls_session: &GlobalSystemMediaTransportControlsSession) -> anyhow::Result<()> {
+    let media_properties = global_system_media_transport_controls_session
+        .TryGetMediaPropertiesAsync()
+        .context("Can not get media properties")?
+        .await
+        .context("Can not get media properties")?;
+    println!("  Media properties:");
+    println!("    Title: {}", diagnostic(media_properties.Title(), |title| title));
+    println!("    Subtitle: {}", diagnostic(media_properties.Subtitle(), |subtitle| subtitle));
+    println!("    Artist: {}", diagnostic(media_properties.Artist(), |artist| artist));
+    println!("    Album title: {}", diagnostic(media_properties.AlbumTitle(), |album_title| album_title));
+    println!("    Album artist: {}", diagnostic(media_properties.AlbumArtist(), |album_artist| album_artist));
+    println!("    Track number: {}", diagnostic(media_properties.TrackNumber(), |track_number| track_number));
+    println!(
+        "    Album track count: {}",
+        diagnostic(media_properties.AlbumTrackCount(), |album_track_count| album_track_count)
+    );
+    println!("    Genres: {}", diagnostic(media_properties.Genres(), |genres| genres.into_iter().join(", ")));
+    println!(
+        "    Playback type: {}",
+        diagnostic(
+            media_properties.PlaybackType().and_then(|playback_type| playback_type.Value()),
+            playback_type_name
+        )
+    );
+    match get_thumbnail(&media_properties).await {
+        Ok(thumbnail) => println!("    Thumbnail: {} ({} bytes)", thumbnail.mime_type, thumbnail.bytes.len()),
+        Err(e) => println!("    Thumbnail: <unavailable: {e}>"),
+    }
+    let playback_info = global_system_media_transport_controls_session
+        .GetPlaybackInfo()
+        .context("Can not get playback info")?;
+    println!("  Playback info:");
+    println!("    Status: {}", diagnostic(playback_info.PlaybackStatus(), playback_status_name));
+    println!(
+        "    Type: {}",
+        diagnostic(playback_info.PlaybackType().and_then(|playback_type| playback_type.Value()), playback_type_name)
+    );
+    println!(
+        "    Auto repeat mode: {}",
+        diagnostic(
+            playback_info.AutoRepeatMode().and_then(|auto_repeat_mode| auto_repeat_mode.Value()),
+            auto_repeat_mode_name
+        )
+    );
+    println!(
+        "    Playback rate: {}",
+        diagnostic(playback_info.PlaybackRate().and_then(|playback_rate| playback_rate.Value()), |playback_rate| {
+            playback_rate
+        })
+    );
+    println!(
+        "    Shuffle active: {}",
+        diagnostic(
+            playback_info.IsShuffleActive().and_then(|shuffle_active| shuffle_active.Value()),
+            |shuffle_active| shuffle_active
+        )
+    );
+    let controls = playback_info.Controls().context("Can not get playback controls")?;
+    println!("  Controls:");
+    for (name, enabled) in [
+        ("Play", controls.IsPlayEnabled()),
+        ("Pause", controls.IsPauseEnabled()),
+        ("Stop", controls.IsStopEnabled()),
+        ("Record", controls.IsRecordEnabled()),
+        ("Fast forward", controls.IsFastForwardEnabled()),
+        ("Rewind", controls.IsRewindEnabled()),
+        ("Next", controls.IsNextEnabled()),
+        ("Previous", controls.IsPreviousEnabled()),
+        ("Channel up", controls.IsChannelUpEnabled()),
+        ("Channel down", controls.IsChannelDownEnabled()),
+        ("Play/pause toggle", controls.IsPlayPauseToggleEnabled()),
+        ("Shuffle", controls.IsShuffleEnabled()),
+        ("Repeat", controls.IsRepeatEnabled()),
+        ("Playback position", controls.IsPlaybackPositionEnabled()),
+        ("Playback rate", controls.IsPlaybackRateEnabled()),
+    ] {
+        println!("    {name}: {}", diagnostic(enabled, |enabled| enabled));
+    }
+    let timeline_properties = global_system_media_transport_controls_session
+        .GetTimelineProperties()
+        .context("Can not get timeline properties")?;
+    println!("  Timeline:");
+    println!("    Start time: {}", diagnostic(timeline_properties.StartTime(), format_time_span));
+    println!("    End time: {}", diagnostic(timeline_properties.EndTime(), format_time_span));
+    println!("    Min seek time: {}", diagnostic(timeline_properties.MinSeekTime(), format_time_span));
+    println!("    Max seek time: {}", diagnostic(timeline_properties.MaxSeekTime(), format_time_span));
+    println!("    Position: {}", diagnostic(timeline_properties.Position(), format_time_span));
+    println!("    Last updated time: {}", diagnostic(timeline_properties.LastUpdatedTime(), format_date_time));
+    Ok(())
+}
+
+async fn command_list_sessions(verbose: bool) -> anyhow::Result<()> {
+    let global_system_media_transport_controls_session_manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
+        .context("Can not get global system media transport controls session manager")?
+        .await
+        .context("Can not get global system media transport controls session manager")?;
+    let global_system_media_transport_controls_sessions = global_system_media_transport_controls_session_manager
+        .GetSessions()
+        .context("Can not get sessions")?;
+    if global_system_media_transport_controls_sessions.Size().context("Can not get sessions")? == 0 {
+        println!("No sessions");
+    }
+    for global_system_media_transport_controls_session in global_system_media_transport_controls_sessions {
+        let session_info = get_session_info(&global_system_media_transport_controls_session, true)
+            .await
+            .context("Can not get session info")?;
+        println!("{}", session_info.source_app_user_mode_id);
+        println!("  Status: {}", playback_status_name(session_info.playback_status));
+        println!("  Title: {}", session_info.title);
+        println!("  Subtitle: {}", session_info.subtitle);
+        println!("  Artist: {}", session_info.artist);
+        println!("  Album title: {}", session_info.album_title);
+        if verbose {
+            print_session_diagnostics(&global_system_media_transport_controls_session).await?;
+        }
+        println!();
+    }
+    Ok(())
+}
+
+async fn command_dump_thumbnail(path: &Path) -> anyhow::Result<()> {
+    let global_system_media_transport_controls_session_manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
+        .context("Can not get global system media transport controls session manager")?
+        .await
+        .context("Can not get global system media transport controls session manager")?;
+    let global_system_media_transport_controls_session = global_system_media_transport_controls_session_manager
+        .GetCurrentSession()
+        .context("No current session")?;
+    let media_properties = global_system_media_transport_controls_session
+        .TryGetMediaPropertiesAsync()
+        .context("Can not get media properties")?
+        .await
+        .context("Can not get media properties")?;
+    let thumbnail = get_thumbnail(&media_properties).await.context("No thumbnail")?;
+    println!("Mime type: {}", thumbnail.mime_type);
+    println!("Size: {} bytes", thumbnail.bytes.len());
+    let path = match mime_type_to_extension(&thumbnail.mime_type) {
+        Ok(extension) => path.with_extension(extension.trim_start_matches('.')),
+        Err(e) => {
+            println!("Keeping the given extension: {e:#}");
+            path.to_path_buf()
+        }
+    };
+    fs::write(&path, &thumbnail.bytes).with_context(|| format!("Can not write {}", path.display()))?;
+    println!("Written to {}", path.display());
+    Ok(())
+}
+
+fn attach_console() {
+    unsafe {
+        let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Event {
+    Update,
+    ConfigChanged,
+    FlushConfig,
+    SwitchProfile(Option<String>),
+    RecordPlayed,
+    ShowCurrentTrack,
+    RefreshMiniPlayerArt,
+    TogglePauseAll,
     Quit,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    sources: Vec<(String, bool)>,
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Source {
+    id: String,
+    enabled: bool,
+    template: Option<String>,
+    allowed_types: Option<Vec<PlaybackType>>,
+    sound: Option<ToastSound>,
+    last_seen: u64,
+}
+
+const SOURCE_LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn unix_time_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Source {
+    fn is_customized(&self) -> bool {
+        !self.enabled || self.template.is_some() || self.allowed_types.is_some() || self.sound.is_some()
+    }
+}
+
+fn prune_sources(sources: &mut Vec<Source>, max_known_sources: usize, keep_id: &str) {
+    while sources.len() > max_known_sources {
+        let Some((i, _)) = sources
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| !source.is_customized() && source.id != keep_id)
+            .min_by_key(|(_, source)| source.last_seen)
+        else {
+            break;
+        };
+        sources.remove(i);
+    }
+}
+
+fn deserialize_sources<'de, D>(deserializer: D) -> Result<Vec<Source>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // Older configs stored every source as an `[id, enabled]` pair
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SourceRepr {
+        Legacy(String, bool),
+        Full(Source),
+    }
+    Ok(<Vec<SourceRepr> as serde::Deserialize>::deserialize(deserializer)?
+        .into_iter()
+        .map(|source_repr| match source_repr {
+            SourceRepr::Legacy(id, enabled) => Source {
+                id,
+                enabled,
+                ..Default::default()
+            },
+            SourceRepr::Full(source) => source,
+        })
+        .collect())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct ToastConfig {
+    duration_ms: u64,
+    gap_ms: u64,
+    scenario: ToastScenario,
+    sound: ToastSound,
+    style: ToastStyle,
+    /// Toasts can not be positioned, so setting this shows a plain popup window on that monitor instead
+    monitor: Option<usize>,
+    custom_xml: Option<PathBuf>,
+    replace_visible: bool,
+    prune_history: bool,
+    line_prefix: String,
+    line_suffix: String,
+    template: Option<String>,
+    title_subtitle_separator: Option<String>,
+    artist_separator: String,
+    name_order: NameOrder,
+    sound_on_source_change: bool,
+    sticky_first: bool,
+    force_aumid: Option<String>,
+    default_thumbnail: Option<PathBuf>,
+}
+
+impl Default for ToastConfig {
+    fn default() -> Self {
+        Self {
+            duration_ms: 3000,
+            gap_ms: 250,
+            scenario: ToastScenario::Default,
+            sound: ToastSound::Silent,
+            style: ToastStyle::Full,
+            monitor: None,
+            custom_xml: None,
+            replace_visible: false,
+            prune_history: true,
+            line_prefix: String::new(),
+            line_suffix: String::new(),
+            template: None,
+            title_subtitle_separator: None,
+            artist_separator: ", ".to_owned(),
+            name_order: NameOrder::SongFirst,
+            sound_on_source_change: false,
+            sticky_first: false,
+            force_aumid: None,
+            default_thumbnail: None,
+        }
+    }
+}
+
+const LEGACY_TOAST_FIELDS: &[(&str, &str)] = &[
+    ("toast_duration_ms", "duration_ms"),
+    ("toast_gap_ms", "gap_ms"),
+    ("toast_scenario", "scenario"),
+    ("toast_sound", "sound"),
+    ("toast_style", "style"),
+    ("toast_monitor", "monitor"),
+    ("custom_toast_xml", "custom_xml"),
+    ("replace_visible", "replace_visible"),
+    ("prune_toast_history", "prune_history"),
+    ("line_prefix", "line_prefix"),
+    ("line_suffix", "line_suffix"),
+    ("template", "template"),
+    ("title_subtitle_separator", "title_subtitle_separator"),
+    ("artist_separator", "artist_separator"),
+    ("name_order", "name_order"),
+    ("sound_on_source_change", "sound_on_source_change"),
+    ("sticky_first_toast", "sticky_first"),
+    ("force_aumid", "force_aumid"),
+    ("default_thumbnail", "default_thumbnail"),
+];
+
+fn migrate_config_value(config_value: &mut serde_json::Value) {
+    let Some(config_object) = config_value.as_object_mut() else {
+        return;
+    };
+    let legacy_fields = LEGACY_TOAST_FIELDS
+        .iter()
+        .filter_map(|(old_name, new_name)| Some((*new_name, config_object.remove(*old_name)?)))
+        .collect::<Vec<_>>();
+    if legacy_fields.is_empty() {
+        return;
+    }
+    let toast_value = config_object.entry("toast").or_insert_with(|| serde_json::Value::Object(Default::default()));
+    if let Some(toast_object) = toast_value.as_object_mut() {
+        for (new_name, value) in legacy_fields {
+            toast_object.entry(new_name).or_insert(value);
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    #[serde(deserialize_with = "deserialize_sources")]
+    sources: Vec<Source>,
+    toast: ToastConfig,
+    show_listening_time: bool,
+    session_retry_attempts: u32,
+    session_retry_delay_ms: u64,
+    allowed_types: Vec<PlaybackType>,
+    notify_on_playback_start: bool,
+    notify_on_playback_stop: bool,
+    playback_started_text: String,
+    playback_stopped_text: String,
+    notify_on_idle: bool,
+    idle_text: String,
+    artwork_path: Option<PathBuf>,
+    suppress_lyrics_titles: bool,
+    exclude_patterns: Vec<String>,
+    coalesce_artists: bool,
+    max_artists: Option<usize>,
+    local_art_fallback: bool,
+    suppress_in_fullscreen: bool,
+    max_known_sources: Option<usize>,
+    show_playing_time: bool,
+    pre_disabled_sources: Vec<String>,
+    default_new_source_enabled: bool,
+    check_updates: bool,
+    last_update_check: u64,
+    notified_update_version: Option<String>,
+    snooze_minutes: u64,
+    max_toasts_per_minute: Option<u32>,
+    summarize_suppressed_toasts: bool,
+    // Some apps recreate their session over and over
+    max_session_recreations_per_minute: Option<u32>,
+    dedup_key: DedupKey,
+    notify_granularity: NotifyGranularity,
+    startup_mode: StartupMode,
+    left_click_action: LeftClickAction,
+    subtitle_as_artist: bool,
+    merge_title_subtitle: bool,
+    clipboard_template: Option<String>,
+    notify_on_repeat: bool,
+    timestamp_format: String,
+    history_path: Option<PathBuf>,
+    min_play_secs_for_history: u64,
+    only_current_session: bool,
+    sessions_snapshot_path: Option<PathBuf>,
+    hide_redundant_album: bool,
+    respect_focus_session: bool,
+    // Windows offers no API to put an app on the priority list, the user still has to allow the source app
+    focus_assist_priority: bool,
+    skip_empty_title: bool,
+    force_thumbnail_png: bool,
+    defer_thumbnail: bool,
+    artist_fallback: Vec<String>,
+    config_write_delay_ms: u64,
+    read_only: bool,
+    forward_to: Option<SocketAddr>,
+    track_link_button: bool,
+    update_art_without_toast: bool,
+    mini_player: bool,
+    mini_player_position: Option<(i32, i32)>,
+    health_port: Option<u16>,
+}
+
+const SESSION_RETRY_ATTEMPTS_RANGE: std::ops::RangeInclusive<u32> = 1..=100;
+const SESSION_RETRY_DELAY_MS_RANGE: std::ops::RangeInclusive<u64> = 1..=1000;
+
+impl Config {
+    fn sanitize(&mut self) {
+        self.session_retry_attempts = self
+            .session_retry_attempts
+            .clamp(*SESSION_RETRY_ATTEMPTS_RANGE.start(), *SESSION_RETRY_ATTEMPTS_RANGE.end());
+        self.session_retry_delay_ms = self
+            .session_retry_delay_ms
+            .clamp(*SESSION_RETRY_DELAY_MS_RANGE.start(), *SESSION_RETRY_DELAY_MS_RANGE.end());
+        if let Some(default_thumbnail) = &self.toast.default_thumbnail
+            && let Err(e) = Thumbnail::from_file(default_thumbnail)
+        {
+            log(format!("Ignoring default thumbnail {}: {e:?}", default_thumbnail.display()));
+            self.toast.default_thumbnail = None;
+        }
+        if self.toast.title_subtitle_separator.as_deref() == Some("") {
+            self.toast.title_subtitle_separator = None;
+        }
+        if let Err(e) = format_timestamp(&self.timestamp_format) {
+            log(format!("Ignoring timestamp format {:?}: {e:#}", self.timestamp_format));
+            self.timestamp_format = RFC3339_TIMESTAMP_FORMAT.to_owned();
+        }
+        if let Err(e) = self.toast.sound.check() {
+            log(format!("Ignoring toast sound: {e:#}"));
+            self.toast.sound = ToastSound::default();
+        }
+        for source in &mut self.sources {
+            if let Some(sound) = &source.sound
+                && let Err(e) = sound.check()
+            {
+                log(format!("Ignoring sound of {}: {e:#}", source.id));
+                source.sound = None;
+            }
+        }
+        if let Some(custom_toast_xml) = &self.toast.custom_xml
+            && let Err(e) = load_custom_toast_xml(custom_toast_xml)
+        {
+            log(format!("Ignoring custom toast xml {}: {e:?}", custom_toast_xml.display()));
+            self.toast.custom_xml = None;
+        }
+        self.artist_fallback.retain(|name| {
+            let known = is_artist_fallback_field(name);
+            if !known {
+                log(format!("Ignoring unknown artist fallback field {name:?}"));
+            }
+            known
+        });
+    }
+}
+
+impl Config {
+    fn is_source_enabled(&self, source_app_user_mode_id: &str) -> bool {
+        self.sources.iter().any(|source| source.id == source_app_user_mode_id && source.enabled)
+    }
+
+    fn is_playback_type_allowed(&self, session_info: &SessionInfo) -> bool {
+        self.sources
+            .iter()
+            .find(|source| source.id == session_info.source_app_user_mode_id)
+            .and_then(|source| source.allowed_types.as_ref())
+            .unwrap_or(&self.allowed_types)
+            .contains(&session_info.playback_type)
+    }
+
+    fn is_excluded(&self, session_info: &SessionInfo) -> bool {
+        let haystack = format!("{}\n{}\n{}", session_info.title, session_info.artist, session_info.album_title).to_lowercase();
+        self.exclude_patterns
+            .iter()
+            .any(|exclude_pattern| !exclude_pattern.is_empty() && haystack.contains(&exclude_pattern.to_lowercase()))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sources: vec![],
+            toast: ToastConfig::default(),
+            show_listening_time: false,
+            session_retry_attempts: 20,
+            session_retry_delay_ms: 50,
+            allowed_types: vec![PlaybackType::Unknown, PlaybackType::Music, PlaybackType::Video, PlaybackType::Image],
+            notify_on_playback_start: false,
+            notify_on_playback_stop: false,
+            playback_started_text: "Playback started".to_owned(),
+            playback_stopped_text: "Playback stopped".to_owned(),
+            notify_on_idle: false,
+            idle_text: "Nothing is playing".to_owned(),
+            artwork_path: None,
+            suppress_lyrics_titles: false,
+            exclude_patterns: vec![],
+            coalesce_artists: false,
+            max_artists: None,
+            local_art_fallback: false,
+            suppress_in_fullscreen: false,
+            max_known_sources: None,
+            show_playing_time: false,
+            pre_disabled_sources: vec![],
+            default_new_source_enabled: true,
+            check_updates: false,
+            last_update_check: 0,
+            notified_update_version: None,
+            snooze_minutes: 30,
+            max_toasts_per_minute: None,
+            summarize_suppressed_toasts: false,
+            max_session_recreations_per_minute: None,
+            dedup_key: DedupKey::Full,
+            notify_granularity: NotifyGranularity::Track,
+            startup_mode: StartupMode::All,
+            left_click_action: LeftClickAction::Nothing,
+            subtitle_as_artist: false,
+            merge_title_subtitle: false,
+            clipboard_template: None,
+            notify_on_repeat: false,
+            timestamp_format: RFC3339_TIMESTAMP_FORMAT.to_owned(),
+            history_path: None,
+            min_play_secs_for_history: 0,
+            only_current_session: false,
+            sessions_snapshot_path: None,
+            hide_redundant_album: false,
+            respect_focus_session: false,
+            focus_assist_priority: false,
+            skip_empty_title: true,
+            force_thumbnail_png: false,
+            defer_thumbnail: false,
+            artist_fallback: vec![],
+            config_write_delay_ms: 500,
+            read_only: false,
+            forward_to: None,
+            track_link_button: false,
+            update_art_without_toast: true,
+            mini_player: false,
+            mini_player_position: None,
+            health_port: None,
+        }
+    }
+}
+
+const CONFIG_PATH_ENV: &str = "NOW_PLAYING_CONFIG";
+
+fn resolve_config_path(config_path_override: Option<&Path>) -> anyhow::Result<(PathBuf, &'static str)> {
+    if let Some(config_path) = config_path_override {
+        return Ok((config_path.to_path_buf(), "--config"));
+    }
+    if let Some(config_path) = env::var_os(CONFIG_PATH_ENV)
+        && !config_path.is_empty()
+    {
+        return Ok((PathBuf::from(config_path), CONFIG_PATH_ENV));
+    }
+    if let Some(project_dirs) = ProjectDirs::from("xyz", "Levitifox", "Now Playing") {
+        return Ok((project_dirs.config_dir().join("config.json"), "default location"));
+    }
+    // Stripped down environments can lack the known folders `ProjectDirs` relies on
+    if let Some(local_app_data) = env::var_os("LOCALAPPDATA")
+        && !local_app_data.is_empty()
+    {
+        return Ok((Path::new(&local_app_data).join("Now Playing").join("config.json"), "LOCALAPPDATA"));
+    }
+    let exe_path = env::current_exe().context("Unable to get config dir")?;
+    let exe_dir = exe_path.parent().ok_or(anyhow!("Unable to get config dir"))?;
+    Ok((exe_dir.join("config.json"), "next to the executable"))
+}
+
+fn load_config(config_path: &Path) -> anyhow::Result<Config> {
+    let config_str = match fs::read_to_string(config_path) {
+        Ok(config_str) => config_str,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).context("Can not read config"),
+    };
+    let mut config_value = serde_json::from_str(&config_str).context("Can not parse config")?;
+    migrate_config_value(&mut config_value);
+    let mut config = serde_json::from_value::<Config>(config_value).context("Can not parse config")?;
+    config.sanitize();
+    Ok(config)
+}
+
+fn config_stem(config_path: &Path) -> String {
+    config_path
+        .file_stem()
+        .map(|file_stem| file_stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "config".to_owned())
+}
+
+fn profile_config_path(config_path: &Path, profile: Option<&str>) -> PathBuf {
+    match profile {
+        Some(profile) => config_path.with_file_name(format!("{}.{profile}.json", config_stem(config_path))),
+        None => config_path.to_path_buf(),
+    }
+}
+
+fn list_profiles(config_path: &Path) -> Vec<String> {
+    let prefix = format!("{}.", config_stem(config_path));
+    let Some(Ok(read_dir)) = config_path.parent().map(fs::read_dir) else {
+        return vec![];
+    };
+    read_dir
+        .filter_map(|dir_entry| {
+            let file_name = dir_entry.ok()?.file_name().into_string().ok()?;
+            let profile = file_name.strip_prefix(&prefix)?.strip_suffix(".json")?;
+            (!profile.is_empty()).then(|| profile.to_owned())
+        })
+        .sorted()
+        .collect()
+}
+
+fn active_profile_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(format!("{}.profile", config_stem(config_path)))
+}
+
+fn read_active_profile(config_path: &Path) -> Option<String> {
+    let active_profile = fs::read_to_string(active_profile_path(config_path)).ok()?;
+    let active_profile = active_profile.trim();
+    (!active_profile.is_empty()).then(|| active_profile.to_owned())
+}
+
+fn command_config_info(config_path_override: Option<&Path>) -> anyhow::Result<()> {
+    let (config_path, origin) = resolve_config_path(config_path_override)?;
+    println!("Config path: {} (from {origin})", config_path.display());
+    println!("Exists: {}", if config_path.exists() { "yes" } else { "no" });
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Failed to load: {e:#}, defaults are used instead");
+            Config::default()
+        }
+    };
+    println!("Effective config:");
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    Ok(())
+}
+
+fn check_config(config_value: &serde_json::Value, config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+    if let (Some(config_object), Ok(serde_json::Value::Object(default_object))) = (config_value.as_object(), serde_json::to_value(Config::default())) {
+        for key in config_object.keys().filter(|key| !default_object.contains_key(*key)) {
+            problems.push(format!("Unknown field `{key}` is ignored"));
+        }
+    }
+    if !SESSION_RETRY_ATTEMPTS_RANGE.contains(&config.session_retry_attempts) {
+        problems.push(format!(
+            "`session_retry_attempts` is {}, it must be between {} and {}",
+            config.session_retry_attempts,
+            SESSION_RETRY_ATTEMPTS_RANGE.start(),
+            SESSION_RETRY_ATTEMPTS_RANGE.end()
+        ));
+    }
+    if !SESSION_RETRY_DELAY_MS_RANGE.contains(&config.session_retry_delay_ms) {
+        problems.push(format!(
+            "`session_retry_delay_ms` is {}, it must be between {} and {}",
+            config.session_retry_delay_ms,
+            SESSION_RETRY_DELAY_MS_RANGE.start(),
+            SESSION_RETRY_DELAY_MS_RANGE.end()
+        ));
+    }
+    if let Some(monitor) = config.toast.monitor {
+        let monitors = get_monitor_work_areas().len();
+        if monitor >= monitors {
+            problems.push(format!(
+                "`toast.monitor` is {monitor}, but there are only {monitors} monitors (counting from 0), the first one is used"
+            ));
+        }
+    }
+    if config.toast.duration_ms == 0 {
+        problems.push("`toast.duration_ms` is 0, toasts would be hidden right away".to_owned());
+    }
+    if config.max_toasts_per_minute == Some(0) {
+        problems.push("`max_toasts_per_minute` is 0, no toast would ever be shown".to_owned());
+    }
+    if config.max_session_recreations_per_minute == Some(0) {
+        problems.push("`max_session_recreations_per_minute` is 0, every new session would be ignored".to_owned());
+    }
+    if config.max_artists == Some(0) {
+        problems.push("`max_artists` is 0, the first artist is still shown".to_owned());
+    }
+    if config.max_known_sources == Some(0) {
+        problems.push("`max_known_sources` is 0, no source could be remembered".to_owned());
+    }
+    if config.toast.title_subtitle_separator.as_deref() == Some("") {
+        problems.push("`toast.title_subtitle_separator` is empty, the locale default is used instead".to_owned());
+    }
+    if let Err(e) = format_timestamp(&config.timestamp_format) {
+        problems.push(format!("`timestamp_format` {:?} is invalid: {e:#}", config.timestamp_format));
+    }
+    for name in config.artist_fallback.iter().filter(|name| !is_artist_fallback_field(name)) {
+        problems.push(format!(
+            "`artist_fallback` contains unknown field {name:?}, known are {}",
+            TEMPLATE_PLACEHOLDERS
+                .iter()
+                .filter(|placeholder| is_artist_fallback_field(placeholder))
+                .join(", ")
+        ));
+    }
+    if config.exclude_patterns.iter().any(String::is_empty) {
+        problems.push("`exclude_patterns` contains an empty pattern, which is ignored".to_owned());
+    }
+    let templates = config
+        .toast
+        .template
+        .iter()
+        .map(|template| ("`toast.template`".to_owned(), template))
+        .chain(config.sources.iter().filter_map(|source| {
+            let template = source.template.as_ref()?;
+            Some((format!("`template` of source {}", source.id), template))
+        }));
+    for (name, template) in templates {
+        for unknown_template_placeholder in unknown_template_placeholders(template) {
+            problems.push(format!(
+                "{name} uses unknown placeholder {{{unknown_template_placeholder}}}, known are {}",
+                TEMPLATE_PLACEHOLDERS.iter().map(|placeholder| format!("{{{placeholder}}}")).join(", ")
+            ));
+        }
+    }
+    if let Some(default_thumbnail) = &config.toast.default_thumbnail
+        && let Err(e) = Thumbnail::from_file(default_thumbnail)
+    {
+        problems.push(format!("`toast.default_thumbnail` {} can not be used: {e:#}", default_thumbnail.display()));
+    }
+    if let Err(e) = config.toast.sound.check() {
+        problems.push(format!("`toast.sound` can not be used: {e:#}"));
+    }
+    for source in &config.sources {
+        if let Some(sound) = &source.sound
+            && let Err(e) = sound.check()
+        {
+            problems.push(format!("`sound` of source {} can not be used: {e:#}", source.id));
+        }
+    }
+    if let Some(custom_toast_xml) = &config.toast.custom_xml
+        && let Err(e) = load_custom_toast_xml(custom_toast_xml)
+    {
+        problems.push(format!("`toast.custom_xml` {} can not be used: {e:#}", custom_toast_xml.display()));
+    }
+    if let Some(artwork_path) = &config.artwork_path
+        && let Some(parent) = artwork_path.parent()
+        && !parent.as_os_str().is_empty()
+        && !parent.is_dir()
+    {
+        problems.push(format!("The directory of `artwork_path` {} does not exist", artwork_path.display()));
+    }
+    problems
+}
+
+const AUTOSTART_KEY: PCWSTR = windows_strings::w!("Software\\Microsoft\\Windows\\CurrentVersion\\Run");
+const AUTOSTART_VALUE: PCWSTR = windows_strings::w!("Now Playing");
+
+fn autostart_command(config_path_override: Option<&Path>) -> anyhow::Result<String> {
+    let mut command = format!("\"{}\"", env::current_exe().context("Can not get executable path")?.display());
+    if let Some(config_path) = config_path_override {
+        command.push_str(&format!(" --config \"{}\"", std::path::absolute(config_path)?.display()));
+    }
+    Ok(command)
+}
+
+fn read_autostart() -> anyhow::Result<Option<String>> {
+    unsafe {
+        let mut size = 0u32;
+        let error = RegGetValueW(HKEY_CURRENT_USER, AUTOSTART_KEY, AUTOSTART_VALUE, RRF_RT_REG_SZ, None, None, Some(&mut size));
+        if error == ERROR_FILE_NOT_FOUND {
+            return Ok(None);
+        }
+        error.ok().context("Can not read autostart entry")?;
+        let mut value = vec![0u16; size as usize / size_of::<u16>()];
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            AUTOSTART_KEY,
+            AUTOSTART_VALUE,
+            RRF_RT_REG_SZ,
+            None,
+            Some(value.as_mut_ptr() as _),
+            Some(&mut size),
+        )
+        .ok()
+        .context("Can not read autostart entry")?;
+        value.truncate(size as usize / size_of::<u16>());
+        Ok(Some(String::from_utf16_lossy(&value).trim_end_matches('\0').to_owned()))
+    }
+}
+
+fn write_autostart(command: &str) -> anyhow::Result<()> {
+    let command = command.encode_utf16().chain(std::iter::once(0)).collect::<Vec<_>>();
+    unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            AUTOSTART_KEY,
+            AUTOSTART_VALUE,
+            REG_SZ.0,
+            Some(command.as_ptr() as _),
+            (command.len() * size_of::<u16>()) as _,
+        )
+        .ok()
+        .context("Can not write autostart entry")
+    }
+}
+
+fn delete_autostart() -> anyhow::Result<bool> {
+    let error = unsafe { RegDeleteKeyValueW(HKEY_CURRENT_USER, AUTOSTART_KEY, AUTOSTART_VALUE) };
+    if error == ERROR_FILE_NOT_FOUND {
+        return Ok(false);
+    }
+    error.ok().context("Can not delete autostart entry")?;
+    Ok(true)
+}
+
+fn command_install(config_path_override: Option<&Path>) -> anyhow::Result<()> {
+    let (config_path, _) = resolve_config_path(config_path_override)?;
+    if config_path.exists() {
+        println!("Config already exists: {}", config_path.display());
+    } else {
+        write_file_atomically(&config_path, serde_json::to_string_pretty(&Config::default())?.as_bytes())
+            .with_context(|| format!("Can not create {}", config_path.display()))?;
+        println!("Created config: {}", config_path.display());
+    }
+    let autostart_command = autostart_command(config_path_override)?;
+    if read_autostart()?.as_deref() == Some(autostart_command.as_str()) {
+        println!("Autostart already enabled");
+    } else {
+        write_autostart(&autostart_command)?;
+        println!("Enabled autostart: {autostart_command}");
+    }
+    Ok(())
+}
+
+fn command_uninstall(config_path_override: Option<&Path>, remove_config: bool) -> anyhow::Result<()> {
+    if delete_autostart()? {
+        println!("Disabled autostart");
+    } else {
+        println!("Autostart already disabled");
+    }
+    if !remove_config {
+        return Ok(());
+    }
+    let (config_path, _) = resolve_config_path(config_path_override)?;
+    let config_paths = list_profiles(&config_path)
+        .into_iter()
+        .map(|profile| profile_config_path(&config_path, Some(&profile)))
+        .chain([active_profile_path(&config_path), config_path.clone()]);
+    let mut removed = false;
+    for config_path in config_paths {
+        match fs::remove_file(&config_path) {
+            Ok(()) => {
+                println!("Removed: {}", config_path.display());
+                removed = true;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("Can not remove {}", config_path.display())),
+        }
+    }
+    if !removed {
+        println!("Config already removed");
+    }
+    Ok(())
+}
+
+fn command_check_config(config_path_override: Option<&Path>) -> anyhow::Result<()> {
+    let (config_path, _) = resolve_config_path(config_path_override)?;
+    let config_str = match fs::read_to_string(&config_path) {
+        Ok(config_str) => config_str,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            println!("{} does not exist, defaults are used", config_path.display());
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("Can not read config"),
+    };
+    let problems = match serde_json::from_str::<serde_json::Value>(&config_str) {
+        Ok(mut config_value) => {
+            migrate_config_value(&mut config_value);
+            match serde_json::from_value::<Config>(config_value.clone()) {
+                Ok(config) => check_config(&config_value, &config),
+                Err(e) => vec![format!("{}: {e}", config_path.display())],
+            }
+        }
+        Err(e) => vec![format!("{}:{}:{}: {e}", config_path.display(), e.line(), e.column())],
+    };
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if !problems.is_empty() {
+        bail!("{} problems found in {}", problems.len(), config_path.display())
+    }
+    println!("{} is valid", config_path.display());
+    Ok(())
+}
+
+fn local_day() -> (u16, u16, u16) {
+    let system_time = unsafe { GetLocalTime() };
+    (system_time.wYear, system_time.wMonth, system_time.wDay)
+}
+
+fn format_listening_time(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes >= 60 {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+fn current_position(session_info: &SessionInfo) -> Option<Duration> {
+    let (position, reported_at) = session_info.timeline_position?;
+    if session_info.playback_status == GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing {
+        Some(position + SystemTime::now().duration_since(reported_at).unwrap_or_default())
+    } else {
+        Some(position)
+    }
+}
+
+fn format_position(position: Duration) -> String {
+    let seconds = position.as_secs();
+    if seconds >= 60 * 60 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ListeningTime {
+    day: (u16, u16, u16),
+    total: Duration,
+    playing_since: Option<Instant>,
+}
+
+impl ListeningTime {
+    fn today(&self) -> Duration {
+        if self.day != local_day() {
+            return Duration::ZERO;
+        }
+        self.total + self.playing_since.map(|playing_since| playing_since.elapsed()).unwrap_or_default()
+    }
+
+    fn update(&mut self, playing: bool) {
+        let now = Instant::now();
+        let today = local_day();
+        if self.day != today {
+            self.day = today;
+            self.total = Duration::ZERO;
+            self.playing_since = self.playing_since.map(|_| now);
+        }
+        if let Some(playing_since) = self.playing_since.take() {
+            self.total += now - playing_since;
+        }
+        if playing {
+            self.playing_since = Some(now);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    listening_times: HashMap<String, ListeningTime>,
+    current_session_info: Option<SessionInfo>,
+    snoozed_until: HashMap<String, Instant>,
+    active_profile: Option<String>,
+    health: Health,
+    mini_player_art: Option<MiniPlayerArt>,
+    mini_player_open: bool,
+    read_only_config: bool,
+    mini_player_revision: u64,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    sessions_tracked: usize,
+    toasts_shown: u64,
+    toasts_dropped: u64,
+    last_error_unix_time: Option<u64>,
+}
+
+impl State {
+    fn snooze_remaining(&self, source_app_user_mode_id: &str) -> Option<Duration> {
+        let snoozed_until = self.snoozed_until.get(source_app_user_mode_id)?;
+        snoozed_until.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero())
+    }
+}
+
+const TEMPLATE_PLACEHOLDERS: &[&str] = &["title", "subtitle", "artist", "album"];
+
+fn is_artist_fallback_field(name: &str) -> bool {
+    name != "artist" && TEMPLATE_PLACEHOLDERS.contains(&name)
+}
+
+fn template_placeholder(session_info: &SessionInfo, name: &str) -> Option<String> {
+    match name {
+        "title" => Some(session_info.title.clone()),
+        "subtitle" => Some(session_info.subtitle.clone()),
+        "artist" => Some(session_info.artist.clone()),
+        "album" => Some(session_info.album_title.clone()),
+        _ => None,
+    }
+}
+
+fn render_template(template: &str, session_info: &SessionInfo) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest
+            .find('}')
+            .and_then(|end| template_placeholder(session_info, &rest[1..end]).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                rendered.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn unknown_template_placeholders(template: &str) -> Vec<&str> {
+    let mut unknown_template_placeholders = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        if let Some(end) = rest.find('}') {
+            let name = &rest[..end];
+            if !name.is_empty() && !name.contains('{') && !TEMPLATE_PLACEHOLDERS.contains(&name) {
+                unknown_template_placeholders.push(name);
+            }
+        }
+    }
+    unknown_template_placeholders
+}
+
+const ARTIST_SEPARATORS: &[&str] = &[",", ";", "/", "&", " feat. ", " feat ", " ft. ", " featuring "];
+
+fn split_artists(artist: &str) -> Vec<&str> {
+    // Separators are ASCII, so lowercasing only ASCII keeps byte offsets valid for the original string
+    let lowercase_artist = artist.to_ascii_lowercase();
+    let mut artists = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < artist.len() {
+        if let Some(separator) = ARTIST_SEPARATORS.iter().find(|separator| lowercase_artist[i..].starts_with(**separator)) {
+            artists.push(&artist[start..i]);
+            i += separator.len();
+            start = i;
+        } else {
+            i += artist[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    artists.push(&artist[start..]);
+    artists.into_iter().map(str::trim).filter(|artist| !artist.is_empty()).collect()
+}
+
+fn coalesce_artists(artist: &str, separator: &str) -> String {
+    split_artists(artist).into_iter().unique_by(|artist| artist.to_lowercase()).join(separator)
+}
+
+fn limit_artists(artist: &str, separator: &str, max_artists: usize) -> String {
+    let artists = split_artists(artist).into_iter().unique_by(|artist| artist.to_lowercase()).collect::<Vec<_>>();
+    if artists.len() <= max_artists.max(1) {
+        return artists.join(separator);
+    }
+    let (shown, rest) = artists.split_at(max_artists.max(1));
+    format!("{} & {} more", shown.join(separator), rest.len())
+}
+
+fn merge_title_subtitle(title: &str, subtitle: &str) -> Option<String> {
+    let (title, subtitle) = (title.trim(), subtitle.trim());
+    let (title_lowercase, subtitle_lowercase) = (title.to_lowercase(), subtitle.to_lowercase());
+    if subtitle.is_empty() || title_lowercase.contains(&subtitle_lowercase) {
+        return Some(title.to_owned());
+    }
+    if subtitle_lowercase.starts_with(&title_lowercase) {
+        return Some(subtitle.to_owned());
+    }
+    if let Some(cut_title) = title.strip_suffix('…').or_else(|| title.strip_suffix("...")) {
+        return Some(format!("{} {subtitle}", cut_title.trim_end()));
+    }
+    let is_cut = title.ends_with([',', ':', '-', '&', '(', '[']) || subtitle.starts_with(|c: char| c.is_lowercase() || ")]".contains(c));
+    is_cut.then(|| format!("{title} {subtitle}"))
+}
+
+fn normalize_session_info(config: &Config, session_info: &SessionInfo) -> SessionInfo {
+    let mut session_info = session_info.clone();
+    if config.merge_title_subtitle
+        && let Some(title) = merge_title_subtitle(&session_info.title, &session_info.subtitle)
+    {
+        session_info.title = title;
+        session_info.subtitle.clear();
+    }
+    if config.subtitle_as_artist && !session_info.subtitle.is_empty() {
+        let subtitle = std::mem::take(&mut session_info.subtitle);
+        session_info.artist = if session_info.artist.is_empty() {
+            subtitle
+        } else {
+            format!("{}{}{}", session_info.artist, config.toast.artist_separator, subtitle)
+        };
+    }
+    if session_info.artist.is_empty()
+        && let Some(artist) = config
+            .artist_fallback
+            .iter()
+            .filter_map(|name| template_placeholder(&session_info, name))
+            .find(|value| !value.is_empty())
+    {
+        session_info.artist = artist;
+    }
+    if config.coalesce_artists {
+        session_info.artist = coalesce_artists(&session_info.artist, &config.toast.artist_separator);
+    }
+    if let Some(max_artists) = config.max_artists {
+        session_info.artist = limit_artists(&session_info.artist, &config.toast.artist_separator, max_artists);
+    }
+    session_info
+}
+
+fn separator_for_langid(langid: u16) -> &'static str {
+    // The primary language is the low 10 bits of the LANGID
+    match langid & 0x3ff {
+        // Japanese and Chinese, where a full-width slash is the usual way to join a title and its credits
+        0x11 | 0x04 => "／",
+        // Korean
+        0x12 => " - ",
+        // Russian, Ukrainian, Belarusian and Bulgarian, which use a spaced em dash
+        0x19 | 0x22 | 0x23 | 0x02 => " — ",
+        _ => " – ",
+    }
+}
+
+fn locale_title_subtitle_separator() -> &'static str {
+    separator_for_langid(unsafe { GetUserDefaultUILanguage() })
+}
+
+fn build_lines(config: &Config, session_info: &SessionInfo) -> [String; 3] {
+    let session_info = &normalize_session_info(config, session_info);
+    let template = config
+        .sources
+        .iter()
+        .find(|source| source.id == session_info.source_app_user_mode_id)
+        .and_then(|source| source.template.as_deref())
+        .or(config.toast.template.as_deref());
+    if let Some(template) = template {
+        let rendered = render_template(template, session_info);
+        let mut lines = rendered.splitn(3, '\n').map(str::to_owned);
+        return [
+            lines.next().unwrap_or_default(),
+            lines.next().unwrap_or_default(),
+            lines.next().unwrap_or_default(),
+        ];
+    }
+    let song = if session_info.subtitle.is_empty() {
+        session_info.title.clone()
+    } else {
+        let title_subtitle_separator = match &config.toast.title_subtitle_separator {
+            Some(title_subtitle_separator) => title_subtitle_separator,
+            None => locale_title_subtitle_separator(),
+        };
+        format!("{}{}{}", session_info.title, title_subtitle_separator, session_info.subtitle)
+    };
+    // Singles often report the title as the album
+    if config.hide_redundant_album && session_info.album_title.trim().to_lowercase() == session_info.title.trim().to_lowercase() {
+        return match config.toast.name_order {
+            NameOrder::ArtistFirst if !session_info.artist.is_empty() => [session_info.artist.clone(), song, String::new()],
+            _ => [song, session_info.artist.clone(), String::new()],
+        };
+    }
+    match config.toast.name_order {
+        NameOrder::ArtistFirst if !session_info.artist.is_empty() => [session_info.artist.clone(), song, session_info.album_title.clone()],
+        _ => [song, session_info.album_title.clone(), session_info.artist.clone()],
+    }
+}
+
+fn build_clipboard_text(config: &Config, session_info: &SessionInfo) -> String {
+    match &config.clipboard_template {
+        Some(clipboard_template) => render_template(clipboard_template, &normalize_session_info(config, session_info)),
+        None => build_lines(config, session_info).into_iter().filter(|line| !line.is_empty()).join(" – "),
+    }
+}
+
+fn markdown_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\`*_[]<>#|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn build_markdown_clipboard_text(config: &Config, session_info: &SessionInfo) -> String {
+    if session_info.title.is_empty() {
+        return build_clipboard_text(config, session_info);
+    }
+    let session_info = normalize_session_info(config, session_info);
+    let title = match track_link(&session_info) {
+        Some(link) => format!("[{}]({link})", markdown_escape(&session_info.title)),
+        None => markdown_escape(&session_info.title),
+    };
+    std::iter::once(format!("**{title}**"))
+        .chain(
+            [&session_info.artist, &session_info.album_title]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| markdown_escape(s)),
+        )
+        .join(" – ")
+}
+
+fn build_toast(config: &Config, session_info: &SessionInfo) -> Toast {
+    let [line_1, line_2, line_3] = match config.toast.style {
+        ToastStyle::Compact => [
+            build_lines(config, session_info).into_iter().filter(|line| !line.is_empty()).join(" – "),
+            String::new(),
+            String::new(),
+        ],
+        ToastStyle::Full => build_lines(config, session_info),
+    };
+    let line_1 = if line_1.is_empty() {
+        line_1
+    } else {
+        format!("{}{line_1}{}", config.toast.line_prefix, config.toast.line_suffix)
+    };
+    Toast {
+        duration: Duration::from_millis(config.toast.duration_ms),
+        source_app_user_mode_id: session_info.source_app_user_mode_id.clone(),
+        line_1,
+        line_2,
+        line_3,
+        thumbnail: session_info.thumbnail.clone().or_else(|| {
+            config
+                .toast
+                .default_thumbnail
+                .as_deref()
+                .and_then(|default_thumbnail| Thumbnail::from_file(default_thumbnail).ok())
+        }),
+        // A scenario chosen by the user wins, those already have their own rules under Do not disturb
+        scenario: if config.focus_assist_priority && config.toast.scenario == ToastScenario::Default && supports_urgent_toasts() {
+            ToastScenario::Urgent
+        } else {
+            config.toast.scenario
+        },
+        sound: config
+            .sources
+            .iter()
+            .find(|source| source.id == session_info.source_app_user_mode_id)
+            .and_then(|source| source.sound.clone())
+            .unwrap_or_else(|| config.toast.sound.clone()),
+        force_aumid: config.toast.force_aumid.clone(),
+        launch_uri: None,
+        replace_visible: config.toast.replace_visible,
+        monitor: config.toast.monitor,
+        style: config.toast.style,
+        custom_xml: config.toast.custom_xml.as_deref().and_then(|custom_toast_xml| {
+            load_custom_toast_xml(custom_toast_xml)
+                .inspect_err(|e| log_rate_limited("custom_toast_xml", format!("Can not load custom toast xml: {e:?}")))
+                .ok()
+        }),
+        prune_history: config.toast.prune_history,
+        link_uri: if config.track_link_button { track_link(session_info) } else { None },
+    }
+}
+
+fn build_notice_toast(config: &Config, source_app_user_mode_id: String, [line_1, line_2, line_3]: [String; 3]) -> Toast {
+    Toast {
+        duration: Duration::from_millis(config.toast.duration_ms),
+        source_app_user_mode_id,
+        line_1,
+        line_2,
+        line_3,
+        thumbnail: None,
+        scenario: ToastScenario::Default,
+        sound: ToastSound::Silent,
+        force_aumid: config.toast.force_aumid.clone(),
+        launch_uri: None,
+        replace_visible: false,
+        monitor: None,
+        style: ToastStyle::Full,
+        custom_xml: None,
+        prune_history: config.toast.prune_history,
+        link_uri: None,
+    }
+}
+
+fn build_playback_toast(config: &Config, session_info: &SessionInfo, text: &str) -> Toast {
+    Toast {
+        line_1: text.to_owned(),
+        line_2: session_info.title.clone(),
+        line_3: session_info.artist.clone(),
+        ..build_toast(config, session_info)
+    }
+}
+
+fn is_playback_stopped(playback_status: GlobalSystemMediaTransportControlsSessionPlaybackStatus) -> bool {
+    use GlobalSystemMediaTransportControlsSessionPlaybackStatus as PlaybackStatus;
+    matches!(playback_status, PlaybackStatus::Closed | PlaybackStatus::Opened | PlaybackStatus::Stopped)
+}
+
+fn get_playback_toasts(config: &Config, prev_session_infos: &[SessionInfo], session_infos: &[SessionInfo]) -> Vec<Toast> {
+    use GlobalSystemMediaTransportControlsSessionPlaybackStatus as PlaybackStatus;
+    let mut playback_toasts = vec![];
+    if config.notify_on_playback_start {
+        for session_info in session_infos {
+            if session_info.playback_status == PlaybackStatus::Playing
+                && let Some(prev_session_info) = prev_session_infos
+                    .iter()
+                    .find(|prev_session_info| prev_session_info.source_app_user_mode_id == session_info.source_app_user_mode_id)
+                && is_playback_stopped(prev_session_info.playback_status)
+                && config.is_source_enabled(&session_info.source_app_user_mode_id)
+            {
+                playback_toasts.push(build_playback_toast(config, session_info, &config.playback_started_text));
+            }
+        }
+    }
+    if config.notify_on_playback_stop {
+        for prev_session_info in prev_session_infos {
+            if matches!(prev_session_info.playback_status, PlaybackStatus::Playing | PlaybackStatus::Paused)
+                && session_infos
+                    .iter()
+                    .find(|session_info| session_info.source_app_user_mode_id == prev_session_info.source_app_user_mode_id)
+                    .is_none_or(|session_info| matches!(session_info.playback_status, PlaybackStatus::Closed | PlaybackStatus::Stopped))
+                && config.is_source_enabled(&prev_session_info.source_app_user_mode_id)
+            {
+                playback_toasts.push(build_playback_toast(config, prev_session_info, &config.playback_stopped_text));
+            }
+        }
+    }
+    if config.notify_on_idle
+        && !session_infos.iter().any(|session_info| session_info.playback_status == PlaybackStatus::Playing)
+        && let Some(prev_session_info) = prev_session_infos
+            .iter()
+            .find(|prev_session_info| prev_session_info.playback_status == PlaybackStatus::Playing)
+        && config.is_source_enabled(&prev_session_info.source_app_user_mode_id)
+    {
+        playback_toasts.push(build_playback_toast(config, prev_session_info, &config.idle_text));
+    }
+    playback_toasts
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry<'a> {
+    time: String,
+    unix_time: u64,
+    source_app_user_mode_id: Cow<'a, str>,
+    title: Cow<'a, str>,
+    subtitle: Cow<'a, str>,
+    artist: Cow<'a, str>,
+    album_title: Cow<'a, str>,
+}
+
+fn append_history(history_path: &Path, timestamp_format: &str, session_info: &SessionInfo) -> anyhow::Result<()> {
+    let history_entry = HistoryEntry {
+        time: format_timestamp(timestamp_format)?,
+        unix_time: unix_time_now(),
+        source_app_user_mode_id: session_info.source_app_user_mode_id.as_str().into(),
+        title: session_info.title.as_str().into(),
+        subtitle: session_info.subtitle.as_str().into(),
+        artist: session_info.artist.as_str().into(),
+        album_title: session_info.album_title.as_str().into(),
+    };
+    if let Some(parent) = history_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(history_path)?;
+    writeln!(file, "{}", serde_json::to_string(&history_entry)?)?;
+    Ok(())
+}
+
+fn record_history(config: &RwLock<Config>, session_info: &SessionInfo) {
+    let (history_path, timestamp_format) = {
+        let config = config.read().unwrap();
+        (config.history_path.clone(), config.timestamp_format.clone())
+    };
+    if let Some(history_path) = history_path
+        && let Err(e) = append_history(&history_path, &timestamp_format, session_info)
+    {
+        log(format!("Unable to append history: {e:?}"))
+    }
+}
+
+fn read_history(history_path: &Path, count: usize) -> anyhow::Result<Vec<HistoryEntry<'static>>> {
+    let history = fs::read_to_string(history_path)?;
+    let history_entries = history
+        .lines()
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(line).ok())
+        .collect::<Vec<_>>();
+    Ok(history_entries.into_iter().rev().take(count).rev().collect())
+}
+
+fn csv_escape(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+fn build_stats_csv(history_entries: &[HistoryEntry]) -> String {
+    let mut source_counts = HashMap::<&str, usize>::new();
+    let mut artist_counts = HashMap::<&str, usize>::new();
+    let mut track_counts = HashMap::<(&str, &str), usize>::new();
+    for history_entry in history_entries {
+        *source_counts.entry(&*history_entry.source_app_user_mode_id).or_default() += 1;
+        if !history_entry.artist.is_empty() {
+            *artist_counts.entry(&*history_entry.artist).or_default() += 1;
+        }
+        *track_counts.entry((&*history_entry.artist, &*history_entry.title)).or_default() += 1;
+    }
+    let mut csv = "kind,source,artist,title,plays\n".to_owned();
+    for (source, plays) in source_counts.into_iter().sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0))) {
+        csv.push_str(&format!("source,{},,,{plays}\n", csv_escape(source)));
+    }
+    for (artist, plays) in artist_counts.into_iter().sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0))) {
+        csv.push_str(&format!("artist,,{},,{plays}\n", csv_escape(artist)));
+    }
+    for ((artist, title), plays) in track_counts.into_iter().sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0))) {
+        csv.push_str(&format!("track,,{},{},{plays}\n", csv_escape(artist), csv_escape(title)));
+    }
+    csv
+}
+
+fn command_export_stats(config_path_override: Option<&Path>, path: &Path, since: Option<i64>, until: Option<i64>) -> anyhow::Result<()> {
+    let (config_path, _) = resolve_config_path(config_path_override)?;
+    let config = load_config(&profile_config_path(&config_path, read_active_profile(&config_path).as_deref()))?;
+    let history_path = config.history_path.ok_or(anyhow!("`history_path` is not set, there is no history to export"))?;
+    let history_entries = match read_history(&history_path, usize::MAX) {
+        Ok(history_entries) => history_entries,
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == ErrorKind::NotFound) => {
+            println!("{} does not exist yet", history_path.display());
+            vec![]
+        }
+        Err(e) => return Err(e).with_context(|| format!("Can not read history {}", history_path.display())),
+    };
+    let history_entries = history_entries
+        .into_iter()
+        .filter(|history_entry| {
+            let day = (history_entry.unix_time / (24 * 60 * 60)) as i64;
+            since.is_none_or(|since| day >= since) && until.is_none_or(|until| day <= until)
+        })
+        .collect::<Vec<_>>();
+    write_file_atomically(path, build_stats_csv(&history_entries).as_bytes()).with_context(|| format!("Can not write {}", path.display()))?;
+    println!("Exported {} plays to {}", history_entries.len(), path.display());
+    Ok(())
+}
+
+const REPLAY_TOAST_DURATION: Duration = Duration::from_millis(1500);
+
+async fn replay_history(config: Arc<RwLock<Config>>, count: usize) {
+    let toasts = {
+        let config = config.read().unwrap();
+        let Some(history_path) = &config.history_path else {
+            log("Nothing to replay, `history_path` is not set");
+            return;
+        };
+        let history_entries = match read_history(history_path, count) {
+            Ok(history_entries) => history_entries,
+            Err(e) => {
+                log(format!("Unable to read history {}: {e:?}", history_path.display()));
+                return;
+            }
+        };
+        history_entries
+            .into_iter()
+            .map(|history_entry| {
+                let session_info = SessionInfo {
+                    source_app_user_mode_id: history_entry.source_app_user_mode_id.into_owned(),
+                    title: history_entry.title.into_owned(),
+                    subtitle: history_entry.subtitle.into_owned(),
+                    artist: history_entry.artist.into_owned(),
+                    album_title: history_entry.album_title.into_owned(),
+                    thumbnail: None,
+                    playback_status: GlobalSystemMediaTransportControlsSessionPlaybackStatus::Closed,
+                    playback_type: PlaybackType::Unknown,
+                    duration: None,
+                    timeline_position: None,
+                };
+                Toast {
+                    duration: REPLAY_TOAST_DURATION,
+                    scenario: ToastScenario::Default,
+                    sound: ToastSound::Silent,
+                    replace_visible: false,
+                    ..build_toast(&config, &session_info)
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    for toast in toasts {
+        if let Err(e) = send_toast(toast).await {
+            log(format!("Unable to replay toast: {e:?}"))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SessionSnapshot<'a> {
+    source_app_user_mode_id: &'a str,
+    title: &'a str,
+    subtitle: &'a str,
+    artist: &'a str,
+    album_title: &'a str,
+    playback_status: &'static str,
+    playback_type: PlaybackType,
+    position_ms: Option<u64>,
+}
+
+fn write_sessions_snapshot(sessions_snapshot_path: &Path, session_infos: &[SessionInfo]) -> anyhow::Result<()> {
+    let session_snapshots = session_infos
+        .iter()
+        .map(|session_info| SessionSnapshot {
+            source_app_user_mode_id: &session_info.source_app_user_mode_id,
+            title: &session_info.title,
+            subtitle: &session_info.subtitle,
+            artist: &session_info.artist,
+            album_title: &session_info.album_title,
+            playback_status: playback_status_name(session_info.playback_status),
+            playback_type: session_info.playback_type,
+            position_ms: current_position(session_info).map(|position| position.as_millis() as u64),
+        })
+        .collect::<Vec<_>>();
+    if let Some(parent) = sessions_snapshot_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_file_atomically(sessions_snapshot_path, serde_json::to_string_pretty(&session_snapshots)?.as_bytes())
+}
+
+const REPEAT_RESTART_POSITION: Duration = Duration::from_secs(5);
+
+// With repeat-one the metadata stays the same and the track starts over while playing, a pause and resume keeps its position
+fn is_repeat(prev_session_info: &SessionInfo, session_info: &SessionInfo) -> bool {
+    use GlobalSystemMediaTransportControlsSessionPlaybackStatus as PlaybackStatus;
+    if prev_session_info != session_info
+        || prev_session_info.playback_status != PlaybackStatus::Playing
+        || session_info.playback_status != PlaybackStatus::Playing
+    {
+        return false;
+    }
+    match (current_position(prev_session_info), current_position(session_info)) {
+        (Some(prev_position), Some(position)) => position < REPEAT_RESTART_POSITION && prev_position > position + REPEAT_RESTART_POSITION,
+        _ => false,
+    }
+}
+
+const LYRICS_TITLE_CHANGES_WINDOW: Duration = Duration::from_secs(6);
+const LYRICS_TITLE_CHANGES_THRESHOLD: u32 = 2;
+
+// Players that put live lyrics into the title keep changing it while the rest of the metadata stays the same
+fn is_lyrics_update(title_changes: &mut HashMap<String, (u32, Instant)>, prev_session_info: Option<&SessionInfo>, session_info: &SessionInfo) -> bool {
+    let source_app_user_mode_id = &session_info.source_app_user_mode_id;
+    let only_title_changed = prev_session_info.is_some_and(|prev_session_info| {
+        prev_session_info.title != session_info.title
+            && prev_session_info.subtitle == session_info.subtitle
+            && prev_session_info.artist == session_info.artist
+            && prev_session_info.album_title == session_info.album_title
+            // Skipping to another track of the same album changes the length, a new lyrics line does not
+            && session_info.duration.is_some()
+            && prev_session_info.duration == session_info.duration
+    });
+    if !only_title_changed {
+        title_changes.remove(source_app_user_mode_id);
+        return false;
+    }
+    let now = Instant::now();
+    let (count, last_change) = title_changes.entry(source_app_user_mode_id.clone()).or_insert((0, now));
+    if now - *last_change > LYRICS_TITLE_CHANGES_WINDOW {
+        *count = 0;
+    }
+    *count += 1;
+    *last_change = now;
+    *count >= LYRICS_TITLE_CHANGES_THRESHOLD
+}
+
+fn write_artwork(artwork_path: &Path, thumbnail: Option<&Thumbnail>, prev_artwork_path: &mut Option<PathBuf>) -> anyhow::Result<()> {
+    let new_artwork_path = thumbnail
+        .and_then(|thumbnail| mime_type_to_extension(&thumbnail.mime_type).ok())
+        .map(|extension| artwork_path.with_extension(extension.trim_start_matches('.')));
+    if let Some(prev_artwork_path) = prev_artwork_path.as_ref()
+        && Some(prev_artwork_path) != new_artwork_path.as_ref()
+    {
+        match fs::remove_file(prev_artwork_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    *prev_artwork_path = None;
+    if let Some(thumbnail) = thumbnail
+        && let Some(new_artwork_path) = new_artwork_path
+    {
+        write_file_atomically(&new_artwork_path, &thumbnail.bytes)?;
+        *prev_artwork_path = Some(new_artwork_path);
+    }
+    Ok(())
+}
+
+fn is_fullscreen_app_running() -> bool {
+    unsafe { SHQueryUserNotificationState() }.is_ok_and(|state| matches!(state, QUNS_BUSY | QUNS_RUNNING_D3D_FULL_SCREEN | QUNS_PRESENTATION_MODE))
+}
+
+/// Focus sessions of the Windows 11 clock app, always `false` where they are not supported
+fn is_focus_session_active() -> bool {
+    FocusSessionManager::IsSupported().unwrap_or(false)
+        && FocusSessionManager::GetDefault()
+            .and_then(|focus_session_manager| focus_session_manager.IsFocusActive())
+            .unwrap_or(false)
+}
+
+async fn send_toast_spaced(toast: Toast, toast_gap: Duration, toast_hidden_times: &mut HashMap<String, Instant>) -> anyhow::Result<()> {
+    let source_app_user_mode_id = toast.source_app_user_mode_id.clone();
+    if let Some(toast_hidden_time) = toast_hidden_times.get(&source_app_user_mode_id) {
+        tokio::time::sleep_until((*toast_hidden_time + toast_gap).into()).await;
+    }
+    send_toast(toast).await?;
+    toast_hidden_times.insert(source_app_user_mode_id, Instant::now());
+    Ok(())
+}
+
+const TOAST_QUEUE_CAPACITY: usize = 16;
+
+const FORWARD_TOAST_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn forward_toast(forward_to: SocketAddr, toast: &Toast) -> anyhow::Result<()> {
+    let mut toast_json = serde_json::to_string(toast)?;
+    toast_json.push('\n');
+    tokio::time::timeout(FORWARD_TOAST_TIMEOUT, async {
+        let mut stream = tokio::net::TcpStream::connect(forward_to).await.context("Can not connect")?;
+        tokio::io::AsyncWriteExt::write_all(&mut stream, toast_json.as_bytes())
+            .await
+            .context("Can not send toast")?;
+        tokio::io::AsyncWriteExt::shutdown(&mut stream).await.context("Can not send toast")
+    })
+    .await
+    .context("Timed out")?
+}
+
+async fn receive_forwarded_toasts(listener: tokio::net::TcpListener, toast_tx: tokio::sync::mpsc::Sender<(Toast, SocketAddr)>) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log(format!("Unable to accept toast connection: {e:?}"));
+                continue;
+            }
+        };
+        let toast_tx = toast_tx.clone();
+        tokio::spawn(async move {
+            let mut lines = tokio::io::AsyncBufReadExt::lines(tokio::io::BufReader::new(stream));
+            loop {
+                match lines.next_line().await {
+                    Ok(Some(line)) => match serde_json::from_str::<Toast>(&line) {
+                        Ok(toast) => {
+                            if toast_tx.send((toast, peer_addr)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => log(format!("Ignoring invalid toast from {peer_addr}: {e}")),
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        log(format!("Lost connection to {peer_addr}: {e}"));
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn is_web_uri(uri: &str) -> bool {
+    let uri = uri.to_ascii_lowercase();
+    uri.starts_with("https://") || uri.starts_with("http://")
+}
+
+// Anyone who can connect can send a toast
+fn sanitize_forwarded_toast(mut toast: Toast) -> Toast {
+    toast.launch_uri = toast.launch_uri.filter(|launch_uri| is_web_uri(launch_uri));
+    toast.link_uri = toast.link_uri.filter(|link_uri| is_web_uri(link_uri));
+    if toast.sound.check().is_err() {
+        toast.sound = ToastSound::Silent;
+    }
+    toast.custom_xml = None;
+    toast.monitor = None;
+    toast
+}
+
+async fn command_listen_toasts(bind: SocketAddr, allow_remote: bool) -> anyhow::Result<()> {
+    if !bind.ip().is_loopback() && !allow_remote {
+        bail!("{bind} is reachable from other hosts and toasts are not authenticated, pass --allow-remote to listen on it anyway")
+    }
+    let listener = tokio::net::TcpListener::bind(bind).await.with_context(|| format!("Can not listen on {bind}"))?;
+    println!("Listening for toasts on {bind}");
+    let (toast_tx, mut toast_rx) = tokio::sync::mpsc::channel(TOAST_QUEUE_CAPACITY);
+    tokio::spawn(receive_forwarded_toasts(listener, toast_tx));
+    while let Some((toast, peer_addr)) = toast_rx.recv().await {
+        if let Err(e) = command_send_toast(sanitize_forwarded_toast(toast), false).await {
+            log(format!("Unable to show toast from {peer_addr}: {e:?}"))
+        }
+    }
+    Ok(())
+}
+
+async fn dispatch_toasts(config: Arc<RwLock<Config>>, state: Arc<RwLock<State>>, mut toast_rx: tokio::sync::mpsc::Receiver<Toast>) {
+    let mut toast_hidden_times = HashMap::<String, Instant>::new();
+    while let Some(toast) = toast_rx.recv().await {
+        let forward_to = config.read().unwrap().forward_to;
+        if let Some(forward_to) = forward_to {
+            let toast = toast.clone();
+            tokio::spawn(async move {
+                if let Err(e) = forward_toast(forward_to, &toast).await {
+                    log_rate_limited("forward_to", format!("Dropping toast forwarded to {forward_to}: {e:?}"));
+                }
+            });
+        }
+        let toast_gap = Duration::from_millis(config.read().unwrap().toast.gap_ms);
+        match send_toast_spaced(toast, toast_gap, &mut toast_hidden_times).await {
+            Ok(()) => state.write().unwrap().health.toasts_shown += 1,
+            Err(e) => {
+                log(format!("Failed to send toast: {e:?}"));
+                state.write().unwrap().health.last_error_unix_time = Some(unix_time_now());
+            }
+        }
+    }
+}
+
+fn queue_toast(toast_tx: &tokio::sync::mpsc::Sender<Toast>, state: &RwLock<State>, toast: Toast) {
+    if let Err(e) = toast_tx.try_send(toast) {
+        let toasts_dropped = {
+            let mut state = state.write().unwrap();
+            state.health.toasts_dropped += 1;
+            state.health.toasts_dropped
+        };
+        let toast = match &e {
+            tokio::sync::mpsc::error::TrySendError::Full(toast) | tokio::sync::mpsc::error::TrySendError::Closed(toast) => toast,
+        };
+        log(format!(
+            "Dropping toast from {}, {toasts_dropped} dropped so far: {e}",
+            toast.source_app_user_mode_id
+        ))
+    }
+}
+
+const THUMBNAIL_BURST_WINDOW: Duration = Duration::from_millis(1500);
+
+const SESSION_FLAP_GRACE: Duration = Duration::from_secs(2);
+
+const TOAST_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct ToastRateLimiter {
+    sent_times: VecDeque<Instant>,
+    suppressed: u32,
+    suppressed_source_app_user_mode_id: Option<String>,
+}
+
+impl ToastRateLimiter {
+    fn expire(&mut self, now: Instant) {
+        while self
+            .sent_times
+            .front()
+            .is_some_and(|sent_time| now.duration_since(*sent_time) >= TOAST_RATE_LIMIT_WINDOW)
+        {
+            self.sent_times.pop_front();
+        }
+    }
+
+    fn admit(&mut self, toast: &Toast, max_toasts_per_minute: Option<u32>, event_tx: &tokio::sync::mpsc::UnboundedSender<Event>) -> bool {
+        let Some(max_toasts_per_minute) = max_toasts_per_minute else {
+            return true;
+        };
+        let now = Instant::now();
+        self.expire(now);
+        if self.sent_times.len() < max_toasts_per_minute as usize {
+            self.sent_times.push_back(now);
+            true
+        } else {
+            self.suppressed += 1;
+            self.suppressed_source_app_user_mode_id = Some(toast.source_app_user_mode_id.clone());
+            if self.suppressed == 1
+                && let Some(window_reset_time) = self.window_reset_time()
+            {
+                let event_tx = event_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(window_reset_time.into()).await;
+                    let _ = event_tx.send(Event::Update);
+                });
+            }
+            false
+        }
+    }
+
+    fn window_reset_time(&self) -> Option<Instant> {
+        self.sent_times.front().map(|sent_time| *sent_time + TOAST_RATE_LIMIT_WINDOW)
+    }
+
+    fn take_suppressed(&mut self, max_toasts_per_minute: Option<u32>) -> Option<(u32, String)> {
+        self.expire(Instant::now());
+        if self.suppressed == 0 || max_toasts_per_minute.is_some_and(|max_toasts_per_minute| self.sent_times.len() >= max_toasts_per_minute as usize) {
+            return None;
+        }
+        let suppressed = std::mem::take(&mut self.suppressed);
+        Some((suppressed, self.suppressed_source_app_user_mode_id.take()?))
+    }
+}
+
+const SESSION_CHURN_WINDOW: Duration = Duration::from_secs(60);
+
+const SESSION_CHURN_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default)]
+struct SessionChurnGuard {
+    recreation_times: HashMap<String, VecDeque<Instant>>,
+    backoff_until: HashMap<String, Instant>,
+}
+
+impl SessionChurnGuard {
+    fn filter(
+        &mut self,
+        session_infos: Vec<SessionInfo>,
+        prev_session_infos: &[SessionInfo],
+        max_session_recreations_per_minute: Option<u32>,
+        event_tx: &tokio::sync::mpsc::UnboundedSender<Event>,
+    ) -> Vec<SessionInfo> {
+        let Some(max_session_recreations_per_minute) = max_session_recreations_per_minute else {
+            self.recreation_times.clear();
+            self.backoff_until.clear();
+            return session_infos;
+        };
+        let now = Instant::now();
+        self.backoff_until.retain(|source_app_user_mode_id, backoff_until| {
+            let backing_off = now < *backoff_until;
+            if !backing_off {
+                log(format!("Resuming updates from {source_app_user_mode_id}"));
+            }
+            backing_off
+        });
+        self.recreation_times.retain(|_, recreation_times| {
+            while recreation_times
+                .front()
+                .is_some_and(|recreation_time| now.duration_since(*recreation_time) >= SESSION_CHURN_WINDOW)
+            {
+                recreation_times.pop_front();
+            }
+            !recreation_times.is_empty()
+        });
+        session_infos
+            .into_iter()
+            .filter_map(|session_info| {
+                let prev_session_info = prev_session_infos
+                    .iter()
+                    .find(|prev_session_info| prev_session_info.source_app_user_mode_id == session_info.source_app_user_mode_id);
+                if self.backoff_until.contains_key(&session_info.source_app_user_mode_id) {
+                    return prev_session_info.cloned();
+                }
+                if prev_session_info.is_some() {
+                    return Some(session_info);
+                }
+                let recreation_times = self.recreation_times.entry(session_info.source_app_user_mode_id.clone()).or_default();
+                recreation_times.push_back(now);
+                if recreation_times.len() <= max_session_recreations_per_minute as usize {
+                    return Some(session_info);
+                }
+                log(format!(
+                    "{} recreated its session {} times within a minute, ignoring it for {} minutes",
+                    session_info.source_app_user_mode_id,
+                    recreation_times.len(),
+                    SESSION_CHURN_BACKOFF.as_secs() / 60
+                ));
+                self.recreation_times.remove(&session_info.source_app_user_mode_id);
+                self.backoff_until
+                    .insert(session_info.source_app_user_mode_id.clone(), now + SESSION_CHURN_BACKOFF);
+                let event_tx = event_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(SESSION_CHURN_BACKOFF).await;
+                    let _ = event_tx.send(Event::Update);
+                });
+                None
+            })
+            .collect()
+    }
+}
+
+async fn toggle_pause_all(global_system_media_transport_controls_session_manager: &GlobalSystemMediaTransportControlsSessionManager) -> anyhow::Result<()> {
+    let playing_sessions = global_system_media_transport_controls_session_manager
+        .GetSessions()
+        .context("Can not get sessions")?
+        .into_iter()
+        .filter(|global_system_media_transport_controls_session| {
+            global_system_media_transport_controls_session
+                .GetPlaybackInfo()
+                .and_then(|playback_info| playback_info.PlaybackStatus())
+                .is_ok_and(|playback_status| playback_status == GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing)
+        })
+        .collect::<Vec<_>>();
+    if playing_sessions.is_empty() {
+        let global_system_media_transport_controls_session = global_system_media_transport_controls_session_manager
+            .GetCurrentSession()
+            .context("No current session")?;
+        global_system_media_transport_controls_session
+            .TryPlayAsync()
+            .context("Can not play")?
+            .await
+            .context("Can not play")?;
+    }
+    for global_system_media_transport_controls_session in playing_sessions {
+        global_system_media_transport_controls_session
+            .TryPauseAsync()
+            .context("Can not pause")?
+            .await
+            .context("Can not pause")?;
+    }
+    Ok(())
+}
+
+fn write_config(config_path: &Path, config: &RwLock<Config>, state: &RwLock<State>) -> anyhow::Result<()> {
+    if state.read().unwrap().read_only_config || config.read().unwrap().read_only {
+        return Ok(());
+    }
+    let profile_config_path = profile_config_path(config_path, state.read().unwrap().active_profile.as_deref());
+    let config_json = serde_json::to_string_pretty(&*config.read().unwrap())?;
+    write_file_atomically(&profile_config_path, config_json.as_bytes()).context("Failed to write config")
 }
 
 async fn command_run_notifer<P>(
     config_path: P,
     config: Arc<RwLock<Config>>,
+    state: Arc<RwLock<State>>,
     event_tx: tokio::sync::mpsc::UnboundedSender<Event>,
     mut event_rx: tokio::sync::mpsc::UnboundedReceiver<Event>,
 ) -> anyhow::Result<()>
@@ -301,6 +3198,7 @@ where
     P: AsRef<Path>,
 {
     let config_path = config_path.as_ref();
+    set_log_timestamp_format(&config.read().unwrap().timestamp_format);
     let global_system_media_transport_controls_session_manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
         .context("Can not get global system media transport controls session manager")?
         .await
@@ -314,52 +3212,407 @@ where
             Ok(())
         }
     }))?;
+    global_system_media_transport_controls_session_manager.CurrentSessionChanged(&TypedEventHandler::new({
+        let event_tx = event_tx.clone();
+        move |_, _| {
+            event_tx
+                .send(Event::Update)
+                .map_err(|e| windows_result::Error::from(std::io::Error::new(ErrorKind::BrokenPipe, e)))?;
+            Ok(())
+        }
+    }))?;
     event_tx.send(Event::Update)?;
     event_tx.send(Event::ConfigChanged)?;
     let mut prev_session_infos = vec![];
+    let mut session_subscriptions = vec![];
+    let (toast_tx, toast_rx) = tokio::sync::mpsc::channel(TOAST_QUEUE_CAPACITY);
+    tokio::spawn(dispatch_toasts(config.clone(), state.clone(), toast_rx));
+    let mut prev_artwork_path = None;
+    let mut title_changes = HashMap::new();
+    let mut last_toast_source = None;
+    let mut toast_rate_limiter = ToastRateLimiter::default();
+    let mut session_churn_guard = SessionChurnGuard::default();
+    let mut recently_gone_session_infos = HashMap::<String, (SessionInfo, Instant)>::new();
+    let mut last_track_change_time = None::<Instant>;
+    let mut config_dirty = false;
+    let mut last_notified_keys = HashMap::<String, String>::new();
+    let mut pending_history = HashMap::<String, (SessionInfo, Instant)>::new();
+    let mut is_first_update = true;
     while let Some(event) = event_rx.recv().await {
         match event {
             Event::Update => {
-                let session_infos = get_session_infos(event_tx.clone()).await.context("Can not get session infos")?;
+                let (retry_attempts, retry_delay, defer_thumbnail) = {
+                    let config = config.read().unwrap();
+                    (
+                        config.session_retry_attempts,
+                        Duration::from_millis(config.session_retry_delay_ms),
+                        config.defer_thumbnail,
+                    )
+                };
+                let in_burst =
+                    defer_thumbnail && last_track_change_time.is_some_and(|last_track_change_time| last_track_change_time.elapsed() < THUMBNAIL_BURST_WINDOW);
+                if in_burst {
+                    let event_tx = event_tx.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(THUMBNAIL_BURST_WINDOW).await;
+                        let _ = event_tx.send(Event::Update);
+                    });
+                }
+                let mut session_infos = get_session_infos(
+                    &global_system_media_transport_controls_session_manager,
+                    event_tx.clone(),
+                    &mut session_subscriptions,
+                    retry_attempts,
+                    retry_delay,
+                    !in_burst,
+                )
+                .await
+                .context("Can not get session infos")?;
+                let current_source_app_user_mode_id = global_system_media_transport_controls_session_manager
+                    .GetCurrentSession()
+                    .and_then(|global_system_media_transport_controls_session| global_system_media_transport_controls_session.SourceAppUserModelId())
+                    .map(|source_app_user_mode_id| source_app_user_mode_id.to_string_lossy())
+                    .ok();
+                if config.read().unwrap().only_current_session {
+                    session_infos.retain(|session_info| Some(&session_info.source_app_user_mode_id) == current_source_app_user_mode_id.as_ref());
+                }
+                let startup_muted_sources = match (std::mem::take(&mut is_first_update), config.read().unwrap().startup_mode) {
+                    (false, _) | (true, StartupMode::All) => HashSet::new(),
+                    (true, StartupMode::Silent) => session_infos.iter().map(|session_info| session_info.source_app_user_mode_id.clone()).collect(),
+                    (true, StartupMode::CurrentOnly) => session_infos
+                        .iter()
+                        .map(|session_info| session_info.source_app_user_mode_id.clone())
+                        .filter(|source_app_user_mode_id| Some(source_app_user_mode_id) != current_source_app_user_mode_id.as_ref())
+                        .collect(),
+                };
+                let max_session_recreations_per_minute = config.read().unwrap().max_session_recreations_per_minute;
+                let mut session_infos = session_churn_guard.filter(session_infos, &prev_session_infos, max_session_recreations_per_minute, &event_tx);
+                state.write().unwrap().health.sessions_tracked = session_infos.len();
+                // `GetSessions` order is arbitrary, notify for the session updated last first, the ones without a timeline go last
+                session_infos.sort_by_key(|session_info| std::cmp::Reverse(session_info.timeline_position.map(|(_, reported_at)| reported_at)));
+                if config.read().unwrap().local_art_fallback {
+                    for session_info in &mut session_infos {
+                        if session_info.thumbnail.is_none() {
+                            session_info.thumbnail = find_local_art(session_info);
+                        }
+                    }
+                }
+                if config.read().unwrap().force_thumbnail_png {
+                    for session_info in &mut session_infos {
+                        if let Some(thumbnail) = session_info.thumbnail.take() {
+                            session_info.thumbnail = Some(force_thumbnail_png(thumbnail).await);
+                        }
+                    }
+                }
+                let sessions_snapshot_path = config.read().unwrap().sessions_snapshot_path.clone();
+                if let Some(sessions_snapshot_path) = sessions_snapshot_path
+                    && let Err(e) = write_sessions_snapshot(&sessions_snapshot_path, &session_infos)
+                {
+                    log(format!("Unable to write sessions snapshot: {e:?}"))
+                }
+                let update_art_without_toast = config.read().unwrap().update_art_without_toast;
+                let late_thumbnail_session_info = {
+                    let mut state = state.write().unwrap();
+                    if let Some(current_session_info) = &state.current_session_info
+                        && let Some(session_info) = session_infos.iter().find(|session_info| *session_info == current_session_info)
+                    {
+                        let mut session_info = session_info.clone();
+                        // Some apps replace the art of the current track with a sharper one later
+                        let late_thumbnail = session_info.thumbnail.is_some()
+                            && (current_session_info.thumbnail.is_none()
+                                || (update_art_without_toast && session_info.thumbnail != current_session_info.thumbnail));
+                        if session_info.thumbnail.is_none() {
+                            session_info.thumbnail = current_session_info.thumbnail.clone();
+                        }
+                        state.current_session_info = Some(session_info.clone());
+                        late_thumbnail.then_some(session_info)
+                    } else {
+                        None
+                    }
+                };
+                if let Some(session_info) = late_thumbnail_session_info {
+                    let artwork_path = config.read().unwrap().artwork_path.clone();
+                    if let Some(artwork_path) = artwork_path
+                        && let Err(e) = write_artwork(&artwork_path, session_info.thumbnail.as_ref(), &mut prev_artwork_path)
+                    {
+                        log(format!("Unable to write artwork: {e:?}"))
+                    }
+                    update_mini_player_art(&state, session_info.thumbnail.as_ref()).await;
+                }
+                {
+                    let listening_times = &mut state.write().unwrap().listening_times;
+                    for (source, listening_time) in listening_times.iter_mut() {
+                        listening_time.update(session_infos.iter().any(|session_info| {
+                            &session_info.source_app_user_mode_id == source
+                                && session_info.playback_status == GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing
+                        }));
+                    }
+                    for session_info in &session_infos {
+                        if session_info.playback_status == GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing
+                            && !listening_times.contains_key(&session_info.source_app_user_mode_id)
+                        {
+                            listening_times.entry(session_info.source_app_user_mode_id.clone()).or_default().update(true);
+                        }
+                    }
+                }
+                let suppress_toasts = {
+                    let config = config.read().unwrap();
+                    (config.suppress_in_fullscreen && is_fullscreen_app_running()) || (config.respect_focus_session && is_focus_session_active())
+                };
+                let max_toasts_per_minute = config.read().unwrap().max_toasts_per_minute;
+                if let Some((suppressed, source_app_user_mode_id)) = toast_rate_limiter.take_suppressed(max_toasts_per_minute)
+                    && config.read().unwrap().summarize_suppressed_toasts
+                    && !suppress_toasts
+                {
+                    let toast = build_notice_toast(
+                        &config.read().unwrap(),
+                        source_app_user_mode_id,
+                        [format!("{suppressed} notifications suppressed"), String::new(), String::new()],
+                    );
+                    queue_toast(&toast_tx, &state, toast);
+                }
+                let playback_toasts = if suppress_toasts {
+                    vec![]
+                } else {
+                    get_playback_toasts(&config.read().unwrap(), &prev_session_infos, &session_infos)
+                };
+                for toast in playback_toasts {
+                    if startup_muted_sources.contains(&toast.source_app_user_mode_id)
+                        || state.read().unwrap().snooze_remaining(&toast.source_app_user_mode_id).is_some()
+                    {
+                        continue;
+                    }
+                    if !toast_rate_limiter.admit(&toast, max_toasts_per_minute, &event_tx) {
+                        continue;
+                    }
+                    queue_toast(&toast_tx, &state, toast);
+                }
+                // Only kept for this update, so a source that started playing without toasting does not make a later track sticky
+                let mut sticky_sources = HashSet::new();
+                if config.read().unwrap().toast.sticky_first {
+                    for session_info in &session_infos {
+                        if session_info.playback_status == GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing
+                            && prev_session_infos
+                                .iter()
+                                .find(|prev_session_info| prev_session_info.source_app_user_mode_id == session_info.source_app_user_mode_id)
+                                .is_none_or(|prev_session_info| is_playback_stopped(prev_session_info.playback_status))
+                        {
+                            sticky_sources.insert(session_info.source_app_user_mode_id.clone());
+                        }
+                    }
+                }
+                let (dedup_key, notify_on_repeat) = {
+                    let config = config.read().unwrap();
+                    (config.dedup_key, config.notify_on_repeat)
+                };
                 for session_info in &session_infos {
-                    if prev_session_infos.contains(session_info) {
+                    let prev_session_info = prev_session_infos
+                        .iter()
+                        .find(|prev_session_info| prev_session_info.source_app_user_mode_id == session_info.source_app_user_mode_id);
+                    let is_repeat = notify_on_repeat && prev_session_info.is_some_and(|prev_session_info| is_repeat(prev_session_info, session_info));
+                    // Switching tabs or windows can drop a session and bring it back right away, that is not a new track
+                    if recently_gone_session_infos
+                        .remove(&session_info.source_app_user_mode_id)
+                        .is_some_and(|(gone_session_info, gone_time)| {
+                            gone_time.elapsed() < SESSION_FLAP_GRACE && is_same_track(dedup_key, &gone_session_info, session_info)
+                        })
+                    {
+                        continue;
+                    }
+                    if !is_repeat
+                        && prev_session_infos
+                            .iter()
+                            .any(|prev_session_info| is_same_track(dedup_key, prev_session_info, session_info))
+                    {
+                        continue;
+                    }
+                    if config.read().unwrap().suppress_lyrics_titles && is_lyrics_update(&mut title_changes, prev_session_info, session_info) {
+                        continue;
+                    }
+                    // Sources briefly report an empty title while switching tracks
+                    if config.read().unwrap().skip_empty_title && session_info.title.trim().is_empty() {
                         continue;
                     }
                     {
-                        let sources = &mut config.write().unwrap().sources;
-                        match sources.iter().find(|(source, _)| source == &session_info.source_app_user_mode_id) {
+                        let mut config = config.write().unwrap();
+                        let max_known_sources = config.max_known_sources;
+                        let new_source_enabled = config.default_new_source_enabled
+                            && !config
+                                .pre_disabled_sources
+                                .iter()
+                                .any(|pre_disabled_source| pre_disabled_source.eq_ignore_ascii_case(&session_info.source_app_user_mode_id));
+                        let sources = &mut config.sources;
+                        let now = unix_time_now();
+                        match sources.iter_mut().find(|source| source.id == session_info.source_app_user_mode_id) {
                             None => {
-                                sources.push((session_info.source_app_user_mode_id.clone(), true));
+                                sources.push(Source {
+                                    id: session_info.source_app_user_mode_id.clone(),
+                                    enabled: new_source_enabled,
+                                    last_seen: now,
+                                    ..Default::default()
+                                });
+                                if let Some(max_known_sources) = max_known_sources {
+                                    prune_sources(sources, max_known_sources, &session_info.source_app_user_mode_id);
+                                }
                                 event_tx.send(Event::ConfigChanged)?;
+                                if !new_source_enabled {
+                                    continue;
+                                }
                             }
-                            Some((_, enabled)) => {
-                                if !*enabled {
+                            Some(source) => {
+                                if now.saturating_sub(source.last_seen) >= SOURCE_LAST_SEEN_RESOLUTION.as_secs() {
+                                    source.last_seen = now;
+                                    event_tx.send(Event::ConfigChanged)?;
+                                }
+                                if !source.enabled {
                                     continue;
                                 }
                             }
                         }
+                        if !config.is_playback_type_allowed(session_info) || config.is_excluded(session_info) {
+                            continue;
+                        }
                     }
-                    let toast = Toast {
-                        duration: Duration::new(3, 0),
-                        source_app_user_mode_id: session_info.source_app_user_mode_id.clone(),
-                        line_1: if session_info.subtitle.is_empty() {
-                            session_info.title.clone()
+                    if startup_muted_sources.contains(&session_info.source_app_user_mode_id) {
+                        continue;
+                    }
+                    state.write().unwrap().current_session_info = Some(session_info.clone());
+                    update_mini_player_art(&state, session_info.thumbnail.as_ref()).await;
+                    last_track_change_time = Some(Instant::now());
+                    let min_play_time = Duration::from_secs(config.read().unwrap().min_play_secs_for_history);
+                    if min_play_time.is_zero() {
+                        record_history(&config, session_info);
+                    } else {
+                        pending_history.insert(session_info.source_app_user_mode_id.clone(), (session_info.clone(), Instant::now()));
+                        let event_tx = event_tx.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(min_play_time).await;
+                            let _ = event_tx.send(Event::RecordPlayed);
+                        });
+                    }
+                    let artwork_path = config.read().unwrap().artwork_path.clone();
+                    if let Some(artwork_path) = artwork_path
+                        && let Err(e) = write_artwork(&artwork_path, session_info.thumbnail.as_ref(), &mut prev_artwork_path)
+                    {
+                        log(format!("Unable to write artwork: {e:?}"))
+                    }
+                    if suppress_toasts || state.read().unwrap().snooze_remaining(&session_info.source_app_user_mode_id).is_some() {
+                        continue;
+                    }
+                    let notified_key = config.read().unwrap().notify_granularity.key(session_info).map(str::to_owned);
+                    if notified_key.is_some() && last_notified_keys.get(&session_info.source_app_user_mode_id) == notified_key.as_ref() {
+                        continue;
+                    }
+                    let mut toast = build_toast(&config.read().unwrap(), session_info);
+                    if sticky_sources.remove(&session_info.source_app_user_mode_id) {
+                        toast.scenario = ToastScenario::Reminder;
+                    }
+                    if config.read().unwrap().toast.sound_on_source_change {
+                        toast.sound = if last_toast_source.as_ref() == Some(&session_info.source_app_user_mode_id) {
+                            ToastSound::Silent
+                        } else if toast.sound == ToastSound::Silent {
+                            ToastSound::Default
                         } else {
-                            format!("{} – {}", session_info.title, session_info.subtitle)
-                        },
-                        line_2: session_info.album_title.clone(),
-                        line_3: session_info.artist.clone(),
-                        thumbnail: session_info.thumbnail.clone(),
-                    };
-                    send_toast(toast).await.context("Failed to send toast")?;
+                            toast.sound
+                        };
+                    }
+                    if !toast_rate_limiter.admit(&toast, max_toasts_per_minute, &event_tx) {
+                        continue;
+                    }
+                    last_toast_source = Some(session_info.source_app_user_mode_id.clone());
+                    if let Some(notified_key) = notified_key {
+                        last_notified_keys.insert(session_info.source_app_user_mode_id.clone(), notified_key);
+                    }
+                    queue_toast(&toast_tx, &state, toast);
+                }
+                let now = Instant::now();
+                recently_gone_session_infos.retain(|_, (_, gone_time)| now.duration_since(*gone_time) < SESSION_FLAP_GRACE);
+                for prev_session_info in &prev_session_infos {
+                    if !session_infos
+                        .iter()
+                        .any(|session_info| session_info.source_app_user_mode_id == prev_session_info.source_app_user_mode_id)
+                    {
+                        recently_gone_session_infos.insert(prev_session_info.source_app_user_mode_id.clone(), (prev_session_info.clone(), now));
+                    }
                 }
                 prev_session_infos = session_infos;
             }
             Event::ConfigChanged => {
-                fs::create_dir_all(config_path.parent().unwrap()).context("Failed to create config dir")?;
-                fs::write(config_path, serde_json::to_string_pretty(&*config.read().unwrap())?).context("Failed to write config")?;
+                let config_write_delay = Duration::from_millis(config.read().unwrap().config_write_delay_ms);
+                if config_write_delay.is_zero() {
+                    write_config(config_path, &config, &state)?;
+                } else if !config_dirty {
+                    config_dirty = true;
+                    let event_tx = event_tx.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(config_write_delay).await;
+                        let _ = event_tx.send(Event::FlushConfig);
+                    });
+                }
+            }
+            Event::FlushConfig => {
+                if std::mem::take(&mut config_dirty) {
+                    write_config(config_path, &config, &state)?;
+                }
+            }
+            Event::SwitchProfile(profile) => match load_config(&profile_config_path(config_path, profile.as_deref())) {
+                Ok(profile_config) => {
+                    if std::mem::take(&mut config_dirty) {
+                        write_config(config_path, &config, &state)?;
+                    }
+                    set_log_timestamp_format(&profile_config.timestamp_format);
+                    *config.write().unwrap() = profile_config;
+                    if let Err(e) = write_file_atomically(&active_profile_path(config_path), profile.as_deref().unwrap_or_default().as_bytes()) {
+                        log(format!("Unable to save active profile: {e:?}"))
+                    }
+                    state.write().unwrap().active_profile = profile;
+                    event_tx.send(Event::Update)?;
+                }
+                Err(e) => log(format!("Unable to switch to profile {}: {e:?}", profile.as_deref().unwrap_or("Default"))),
+            },
+            Event::RecordPlayed => {
+                let min_play_time = Duration::from_secs(config.read().unwrap().min_play_secs_for_history);
+                pending_history.retain(|_, (pending_session_info, became_current)| {
+                    let Some(session_info) = prev_session_infos.iter().find(|session_info| *session_info == pending_session_info) else {
+                        return false;
+                    };
+                    if became_current.elapsed() < min_play_time {
+                        return true;
+                    }
+                    record_history(&config, session_info);
+                    false
+                });
+            }
+            Event::ShowCurrentTrack => {
+                let current_session_info = state.read().unwrap().current_session_info.clone();
+                if let Some(session_info) = current_session_info {
+                    let toast = Toast {
+                        replace_visible: true,
+                        ..build_toast(&config.read().unwrap(), &session_info)
+                    };
+                    queue_toast(&toast_tx, &state, toast);
+                }
+            }
+            Event::RefreshMiniPlayerArt => {
+                let thumbnail = state
+                    .read()
+                    .unwrap()
+                    .current_session_info
+                    .as_ref()
+                    .and_then(|session_info| session_info.thumbnail.clone());
+                update_mini_player_art(&state, thumbnail.as_ref()).await;
+            }
+            Event::TogglePauseAll => {
+                if let Err(e) = toggle_pause_all(&global_system_media_transport_controls_session_manager).await {
+                    log(format!("Unable to toggle playback: {e:?}"))
+                }
+            }
+            Event::Quit => {
+                if config_dirty {
+                    write_config(config_path, &config, &state)?;
+                }
+                break;
             }
-            Event::Quit => break,
         }
     }
     Ok(())
@@ -376,31 +3629,663 @@ enum PreferredAppMode {
     Max = 4,
 }
 
-type SetPreferredAppModeFn = unsafe extern "system" fn(PreferredAppMode) -> PreferredAppMode;
-
-fn enable_dark_mode() {
-    unsafe {
-        let uxtheme_hmodule = LoadLibraryA(windows_strings::s!("uxtheme.dll")).unwrap_or_default();
-        let set_preferred_app_mode: Option<SetPreferredAppModeFn> = std::mem::transmute(GetProcAddress(uxtheme_hmodule, PCSTR(135 as _)));
-        if let Some(set_preferred_app_mode) = set_preferred_app_mode {
-            set_preferred_app_mode(PreferredAppMode::AllowDark);
+type SetPreferredAppModeFn = unsafe extern "system" fn(PreferredAppMode) -> PreferredAppMode;
+type RtlGetVersionFn = unsafe extern "system" fn(*mut OSVERSIONINFOW) -> i32;
+
+/// Ordinal 135 of uxtheme.dll is `SetPreferredAppMode` since Windows 10 1903, before that it had another signature
+const SET_PREFERRED_APP_MODE_MIN_BUILD: u32 = 18362;
+
+/// `GetVersionExW` reports an older version to binaries without a manifest, `RtlGetVersion` does not
+fn windows_build_number() -> anyhow::Result<u32> {
+    unsafe {
+        let ntdll_hmodule = GetModuleHandleA(windows_strings::s!("ntdll.dll")).context("Can not get ntdll.dll")?;
+        let rtl_get_version: Option<RtlGetVersionFn> = std::mem::transmute(GetProcAddress(ntdll_hmodule, windows_strings::s!("RtlGetVersion")));
+        let rtl_get_version = rtl_get_version.ok_or(anyhow!("Can not find RtlGetVersion"))?;
+        let mut os_version_info = OSVERSIONINFOW {
+            dwOSVersionInfoSize: size_of::<OSVERSIONINFOW>() as _,
+            ..Default::default()
+        };
+        if rtl_get_version(&mut os_version_info) != 0 {
+            bail!("RtlGetVersion failed")
+        }
+        Ok(os_version_info.dwBuildNumber)
+    }
+}
+
+/// Lets the tray menu follow the dark theme through an undocumented uxtheme.dll export
+fn enable_dark_mode() -> anyhow::Result<()> {
+    let build_number = windows_build_number()?;
+    if build_number < SET_PREFERRED_APP_MODE_MIN_BUILD {
+        bail!("Windows build {build_number} is older than {SET_PREFERRED_APP_MODE_MIN_BUILD}")
+    }
+    unsafe {
+        let uxtheme_hmodule = LoadLibraryA(windows_strings::s!("uxtheme.dll")).context("Can not load uxtheme.dll")?;
+        if uxtheme_hmodule.is_invalid() {
+            bail!("Can not load uxtheme.dll")
+        }
+        let set_preferred_app_mode: Option<SetPreferredAppModeFn> = std::mem::transmute(GetProcAddress(uxtheme_hmodule, PCSTR(135 as _)));
+        let set_preferred_app_mode = set_preferred_app_mode.ok_or(anyhow!("uxtheme.dll has no ordinal 135"))?;
+        set_preferred_app_mode(PreferredAppMode::AllowDark);
+    }
+    Ok(())
+}
+
+fn set_clipboard_text(hwnd: HWND, text: &str) -> anyhow::Result<()> {
+    let text = text.encode_utf16().chain(std::iter::once(0)).collect::<Vec<_>>();
+    unsafe {
+        OpenClipboard(Some(hwnd)).context("Can not open clipboard")?;
+        let aux = || -> anyhow::Result<()> {
+            EmptyClipboard().context("Can not empty clipboard")?;
+            let hglobal = GlobalAlloc(GMEM_MOVEABLE, text.len() * size_of::<u16>()).context("Can not allocate clipboard memory")?;
+            let ptr = GlobalLock(hglobal) as *mut u16;
+            if ptr.is_null() {
+                let _ = GlobalFree(Some(hglobal));
+                bail!("Can not lock clipboard memory")
+            }
+            std::ptr::copy_nonoverlapping(text.as_ptr(), ptr, text.len());
+            let _ = GlobalUnlock(hglobal);
+            // The clipboard owns the memory once it is set, so only free it on failure
+            if let Err(e) = SetClipboardData(CF_UNICODETEXT.0 as _, Some(HANDLE(hglobal.0))) {
+                let _ = GlobalFree(Some(hglobal));
+                return Err(e).context("Can not set clipboard data");
+            }
+            Ok(())
+        };
+        let result = aux();
+        CloseClipboard().context("Can not close clipboard")?;
+        result
+    }
+}
+
+fn force_foreground_window(hwnd: HWND) -> bool {
+    unsafe {
+        if SetForegroundWindow(hwnd).as_bool() {
+            return true;
+        }
+        // Windows only lets the thread that owns the foreground window, or the one that received the last input, change it
+        let current_thread_id = GetCurrentThreadId();
+        let foreground_thread_id = GetWindowThreadProcessId(GetForegroundWindow(), None);
+        if foreground_thread_id != 0 && foreground_thread_id != current_thread_id && AttachThreadInput(current_thread_id, foreground_thread_id, true).as_bool()
+        {
+            let result = SetForegroundWindow(hwnd).as_bool();
+            let _ = AttachThreadInput(current_thread_id, foreground_thread_id, false);
+            if result {
+                return true;
+            }
+        }
+        let input = INPUT {
+            r#type: INPUT_MOUSE,
+            ..Default::default()
+        };
+        SendInput(&[input], size_of::<INPUT>() as _);
+        SetForegroundWindow(hwnd).as_bool()
+    }
+}
+
+const SETTINGS_WINDOW_CLASS: PCWSTR = windows_strings::w!("now-playing-settings");
+
+struct SettingsWindowData {
+    config: Arc<RwLock<Config>>,
+    event_tx: UnboundedSender<Event>,
+    source_ids: Vec<String>,
+    toast_duration_edit: HWND,
+    toast_gap_edit: HWND,
+    template_edit: HWND,
+    show_listening_time_checkbox: HWND,
+    sticky_first_toast_checkbox: HWND,
+    suppress_lyrics_titles_checkbox: HWND,
+    sources_list_box: HWND,
+}
+
+const ID_SETTINGS_SAVE: usize = 1;
+const ID_SETTINGS_CANCEL: usize = 2;
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn create_control(
+    parent: HWND,
+    class: PCWSTR,
+    text: &str,
+    style: WINDOW_STYLE,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    id: usize,
+) -> anyhow::Result<HWND> {
+    unsafe {
+        let hwnd = CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            class,
+            &HSTRING::from(text),
+            style | WS_CHILD | WS_VISIBLE,
+            x,
+            y,
+            width,
+            height,
+            Some(parent),
+            Some(HMENU(id as _)),
+            None,
+            None,
+        )
+        .context("Can not create control")?;
+        SendMessageW(hwnd, WM_SETFONT, Some(WPARAM(GetStockObject(DEFAULT_GUI_FONT).0 as _)), Some(LPARAM(1)));
+        Ok(hwnd)
+    }
+}
+
+unsafe fn get_window_text(hwnd: HWND) -> String {
+    unsafe {
+        let mut buffer = vec![0; GetWindowTextLengthW(hwnd) as usize + 1];
+        let length = GetWindowTextW(hwnd, &mut buffer);
+        String::from_utf16_lossy(&buffer[..length.max(0) as usize])
+    }
+}
+
+fn open_settings_window(config: Arc<RwLock<Config>>, event_tx: UnboundedSender<Event>) -> anyhow::Result<()> {
+    unsafe {
+        if let Ok(hwnd) = FindWindowW(SETTINGS_WINDOW_CLASS, PCWSTR::null()) {
+            let _ = SetForegroundWindow(hwnd);
+            return Ok(());
+        }
+        let instance = GetModuleHandleA(None)?;
+        let hwnd = CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            SETTINGS_WINDOW_CLASS,
+            windows_strings::w!("Now Playing Settings"),
+            WS_CAPTION | WS_SYSMENU,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            440,
+            450,
+            None,
+            None,
+            Some(instance.into()),
+            None,
+        )
+        .context("Can not create settings window")?;
+        let edit = windows_strings::w!("EDIT");
+        let button = windows_strings::w!("BUTTON");
+        let label = windows_strings::w!("STATIC");
+        let checkbox_style = WINDOW_STYLE(BS_AUTOCHECKBOX as _) | WS_TABSTOP;
+        let edit_style = WINDOW_STYLE(ES_AUTOHSCROLL as _) | WS_BORDER | WS_TABSTOP;
+        let config_guard = config.read().unwrap();
+        create_control(hwnd, label, "Toast duration (ms)", WINDOW_STYLE::default(), 10, 13, 180, 20, 0)?;
+        let toast_duration_edit = create_control(
+            hwnd,
+            edit,
+            &config_guard.toast.duration_ms.to_string(),
+            edit_style | WINDOW_STYLE(ES_NUMBER as _),
+            200,
+            10,
+            210,
+            22,
+            0,
+        )?;
+        create_control(hwnd, label, "Gap between toasts (ms)", WINDOW_STYLE::default(), 10, 43, 180, 20, 0)?;
+        let toast_gap_edit = create_control(
+            hwnd,
+            edit,
+            &config_guard.toast.gap_ms.to_string(),
+            edit_style | WINDOW_STYLE(ES_NUMBER as _),
+            200,
+            40,
+            210,
+            22,
+            0,
+        )?;
+        create_control(hwnd, label, "Template (empty for default)", WINDOW_STYLE::default(), 10, 73, 180, 20, 0)?;
+        let template_edit = create_control(
+            hwnd,
+            edit,
+            &config_guard.toast.template.clone().unwrap_or_default().replace('\n', "\r\n"),
+            edit_style | WINDOW_STYLE((ES_MULTILINE | ES_AUTOVSCROLL | ES_WANTRETURN) as _),
+            200,
+            70,
+            210,
+            60,
+            0,
+        )?;
+        let show_listening_time_checkbox = create_control(hwnd, button, "Show listening time in the tray menu", checkbox_style, 10, 140, 400, 20, 0)?;
+        let sticky_first_toast_checkbox = create_control(
+            hwnd,
+            button,
+            "Keep the first toast after playback starts until dismissed",
+            checkbox_style,
+            10,
+            165,
+            400,
+            20,
+            0,
+        )?;
+        let suppress_lyrics_titles_checkbox = create_control(hwnd, button, "Suppress lyrics shown as titles", checkbox_style, 10, 190, 400, 20, 0)?;
+        for (checkbox, checked) in [
+            (show_listening_time_checkbox, config_guard.show_listening_time),
+            (sticky_first_toast_checkbox, config_guard.toast.sticky_first),
+            (suppress_lyrics_titles_checkbox, config_guard.suppress_lyrics_titles),
+        ] {
+            SendMessageW(checkbox, BM_SETCHECK, Some(WPARAM(checked as _)), None);
+        }
+        create_control(hwnd, label, "Enabled sources", WINDOW_STYLE::default(), 10, 220, 400, 20, 0)?;
+        let sources_list_box = create_control(
+            hwnd,
+            windows_strings::w!("LISTBOX"),
+            "",
+            WINDOW_STYLE((LBS_MULTIPLESEL | LBS_NOINTEGRALHEIGHT) as _) | WS_BORDER | WS_VSCROLL | WS_TABSTOP,
+            10,
+            240,
+            400,
+            120,
+            0,
+        )?;
+        let source_ids = config_guard.sources.iter().map(|source| source.id.clone()).collect::<Vec<_>>();
+        for (i, source) in config_guard.sources.iter().enumerate() {
+            SendMessageW(
+                sources_list_box,
+                LB_ADDSTRING,
+                None,
+                Some(LPARAM(HSTRING::from(source.id.as_str()).as_ptr() as _)),
+            );
+            SendMessageW(sources_list_box, LB_SETSEL, Some(WPARAM(source.enabled as _)), Some(LPARAM(i as _)));
+        }
+        drop(config_guard);
+        create_control(
+            hwnd,
+            button,
+            "Save",
+            WINDOW_STYLE(BS_DEFPUSHBUTTON as _) | WS_TABSTOP,
+            220,
+            375,
+            90,
+            26,
+            ID_SETTINGS_SAVE,
+        )?;
+        create_control(
+            hwnd,
+            button,
+            "Cancel",
+            WINDOW_STYLE(BS_PUSHBUTTON as _) | WS_TABSTOP,
+            320,
+            375,
+            90,
+            26,
+            ID_SETTINGS_CANCEL,
+        )?;
+        let settings_window_data = SettingsWindowData {
+            config,
+            event_tx,
+            source_ids,
+            toast_duration_edit,
+            toast_gap_edit,
+            template_edit,
+            show_listening_time_checkbox,
+            sticky_first_toast_checkbox,
+            suppress_lyrics_titles_checkbox,
+            sources_list_box,
+        };
+        SetWindowLongPtrW(hwnd, GWLP_USERDATA, Box::into_raw(Box::new(settings_window_data)) as _);
+        let _ = ShowWindow(hwnd, SW_SHOW);
+        let _ = SetForegroundWindow(hwnd);
+        Ok(())
+    }
+}
+
+fn save_settings(settings_window_data: &SettingsWindowData) -> anyhow::Result<()> {
+    unsafe {
+        let mut config = settings_window_data.config.write().unwrap();
+        if let Ok(toast_duration_ms) = get_window_text(settings_window_data.toast_duration_edit).trim().parse() {
+            config.toast.duration_ms = toast_duration_ms;
+        }
+        if let Ok(toast_gap_ms) = get_window_text(settings_window_data.toast_gap_edit).trim().parse() {
+            config.toast.gap_ms = toast_gap_ms;
+        }
+        let template = get_window_text(settings_window_data.template_edit).replace("\r\n", "\n");
+        config.toast.template = if template.trim().is_empty() { None } else { Some(template) };
+        let is_checked = |checkbox: HWND| SendMessageW(checkbox, BM_GETCHECK, None, None).0 == 1;
+        config.show_listening_time = is_checked(settings_window_data.show_listening_time_checkbox);
+        config.toast.sticky_first = is_checked(settings_window_data.sticky_first_toast_checkbox);
+        config.suppress_lyrics_titles = is_checked(settings_window_data.suppress_lyrics_titles_checkbox);
+        for (i, source_id) in settings_window_data.source_ids.iter().enumerate() {
+            let selected = SendMessageW(settings_window_data.sources_list_box, LB_GETSEL, Some(WPARAM(i)), None).0 > 0;
+            if let Some(source) = config.sources.iter_mut().find(|source| &source.id == source_id) {
+                source.enabled = selected;
+            }
+        }
+        config.sanitize();
+    }
+    settings_window_data.event_tx.send(Event::ConfigChanged)?;
+    Ok(())
+}
+
+extern "system" fn settings_wndproc(hwnd: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    unsafe {
+        let settings_window_data_ptr = GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *mut SettingsWindowData;
+        match message {
+            WM_COMMAND if !settings_window_data_ptr.is_null() => {
+                match wparam.0 & 0xffff {
+                    ID_SETTINGS_SAVE => {
+                        if let Err(e) = save_settings(&*settings_window_data_ptr) {
+                            log(format!("Unable to save settings: {e:?}"))
+                        }
+                        let _ = DestroyWindow(hwnd);
+                    }
+                    ID_SETTINGS_CANCEL => {
+                        let _ = DestroyWindow(hwnd);
+                    }
+                    _ => (),
+                }
+                LRESULT(0)
+            }
+            WM_DESTROY => {
+                if !settings_window_data_ptr.is_null() {
+                    SetWindowLongPtrW(hwnd, GWLP_USERDATA, 0);
+                    drop(Box::from_raw(settings_window_data_ptr));
+                }
+                LRESULT(0)
+            }
+            _ => DefWindowProcW(hwnd, message, wparam, lparam),
+        }
+    }
+}
+
+const POPUP_WINDOW_CLASS: PCWSTR = windows_strings::w!("now-playing-popup");
+const POPUP_WIDTH: i32 = 360;
+const POPUP_HEIGHT: i32 = 92;
+const POPUP_MARGIN: i32 = 16;
+const ID_POPUP_TIMER: usize = 1;
+
+fn get_monitor_work_areas() -> Vec<RECT> {
+    extern "system" fn callback(hmonitor: HMONITOR, _: HDC, _: *mut RECT, lparam: LPARAM) -> BOOL {
+        unsafe {
+            let work_areas = &mut *(lparam.0 as *mut Vec<RECT>);
+            let mut monitor_info = MONITORINFO {
+                cbSize: size_of::<MONITORINFO>() as _,
+                ..Default::default()
+            };
+            if GetMonitorInfoW(hmonitor, &mut monitor_info).as_bool() {
+                work_areas.push(monitor_info.rcWork);
+            }
+            true.into()
+        }
+    }
+    let mut work_areas = Vec::<RECT>::new();
+    unsafe {
+        let _ = EnumDisplayMonitors(None, None, Some(callback), LPARAM(&mut work_areas as *mut _ as _));
+    }
+    work_areas
+}
+
+extern "system" fn popup_wndproc(hwnd: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    unsafe {
+        match message {
+            // The labels do not take clicks, so a click anywhere dismisses the popup
+            WM_TIMER | WM_LBUTTONUP => {
+                let _ = DestroyWindow(hwnd);
+                LRESULT(0)
+            }
+            WM_DESTROY => {
+                PostQuitMessage(0);
+                LRESULT(0)
+            }
+            _ => DefWindowProcW(hwnd, message, wparam, lparam),
+        }
+    }
+}
+
+fn show_popup(toast: &Toast, monitor: usize) -> anyhow::Result<()> {
+    let work_areas = get_monitor_work_areas();
+    if monitor >= work_areas.len() {
+        log_rate_limited(
+            "toast_monitor",
+            format!("Monitor {monitor} does not exist, there are {}, showing on the first one", work_areas.len()),
+        );
+    }
+    let work_area = work_areas.get(monitor).or(work_areas.first()).copied().ok_or(anyhow!("No monitors found"))?;
+    unsafe {
+        let instance = GetModuleHandleA(None)?;
+        let wc = WNDCLASSW {
+            hCursor: LoadCursorW(None, IDC_ARROW)?,
+            hInstance: instance.into(),
+            hbrBackground: GetSysColorBrush(COLOR_BTNFACE),
+            lpszClassName: POPUP_WINDOW_CLASS,
+            lpfnWndProc: Some(popup_wndproc),
+            ..Default::default()
+        };
+        // Every popup after the first finds the class already registered, `listen-toasts` shows many of them in one process
+        if RegisterClassW(&wc) == 0 && GetLastError() != ERROR_CLASS_ALREADY_EXISTS {
+            bail!("Can not register popup window class")
+        }
+        let hwnd = CreateWindowExW(
+            WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+            POPUP_WINDOW_CLASS,
+            windows_strings::w!("Now Playing"),
+            WS_POPUP | WS_BORDER,
+            work_area.right - POPUP_WIDTH - POPUP_MARGIN,
+            work_area.bottom - POPUP_HEIGHT - POPUP_MARGIN,
+            POPUP_WIDTH,
+            POPUP_HEIGHT,
+            None,
+            None,
+            Some(instance.into()),
+            None,
+        )
+        .context("Can not create popup window")?;
+        let label = windows_strings::w!("STATIC");
+        for (i, line) in [&toast.line_1, &toast.line_2, &toast.line_3].into_iter().enumerate() {
+            create_control(
+                hwnd,
+                label,
+                line,
+                WINDOW_STYLE(SS_ENDELLIPSIS.0),
+                12,
+                12 + 24 * i as i32,
+                POPUP_WIDTH - 24,
+                20,
+                0,
+            )?;
+        }
+        if matches!(toast.scenario, ToastScenario::Default | ToastScenario::Urgent) {
+            SetTimer(Some(hwnd), ID_POPUP_TIMER, toast.duration.as_millis() as _, None);
+        }
+        let _ = ShowWindow(hwnd, SW_SHOWNOACTIVATE);
+        let mut message = MSG::default();
+        while GetMessageW(&mut message, None, 0, 0).into() {
+            let _ = TranslateMessage(&message);
+            DispatchMessageW(&message);
+        }
+    }
+    Ok(())
+}
+
+/// The tooltip is truncated to fit, without splitting a surrogate pair
+fn tray_tip(text: &str) -> [u16; 128] {
+    let mut tip = [0; 128];
+    let mut len = 0;
+    for c in text.chars() {
+        let mut buf = [0; 2];
+        let encoded = c.encode_utf16(&mut buf);
+        if len + encoded.len() > tip.len() - 1 {
+            break;
+        }
+        tip[len..len + encoded.len()].copy_from_slice(encoded);
+        len += encoded.len();
+    }
+    tip
+}
+
+const MINI_PLAYER_WINDOW_CLASS: PCWSTR = windows_strings::w!("now-playing-mini-player");
+const MINI_PLAYER_WIDTH: i32 = 340;
+const MINI_PLAYER_HEIGHT: i32 = 88;
+const MINI_PLAYER_PADDING: i32 = 12;
+
+struct MiniPlayerWindowData {
+    config: Arc<RwLock<Config>>,
+    state: Arc<RwLock<State>>,
+    event_tx: UnboundedSender<Event>,
+}
+
+unsafe fn paint_mini_player(hwnd: HWND, mini_player_window_data: &MiniPlayerWindowData) {
+    unsafe {
+        let mut paint_struct = PAINTSTRUCT::default();
+        let hdc = BeginPaint(hwnd, &mut paint_struct);
+        let mut client_rect = RECT::default();
+        let _ = GetClientRect(hwnd, &mut client_rect);
+        FillRect(hdc, &client_rect, GetSysColorBrush(COLOR_BTNFACE));
+        let config = mini_player_window_data.config.read().unwrap();
+        let state = mini_player_window_data.state.read().unwrap();
+        let art_size = MINI_PLAYER_ART_SIZE as i32;
+        if let Some(mini_player_art) = &state.mini_player_art {
+            let bitmap_info = BITMAPINFO {
+                bmiHeader: BITMAPINFOHEADER {
+                    biSize: size_of::<BITMAPINFOHEADER>() as _,
+                    biWidth: mini_player_art.width as _,
+                    // A negative height makes the rows top-down, as they are decoded
+                    biHeight: -(mini_player_art.height as i32),
+                    biPlanes: 1,
+                    biBitCount: 32,
+                    biCompression: BI_RGB.0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let (width, height) = (mini_player_art.width as i32, mini_player_art.height as i32);
+            StretchDIBits(
+                hdc,
+                MINI_PLAYER_PADDING + (art_size - width) / 2,
+                MINI_PLAYER_PADDING + (art_size - height) / 2,
+                width,
+                height,
+                0,
+                0,
+                width,
+                height,
+                Some(mini_player_art.pixels.as_ptr() as _),
+                &bitmap_info,
+                DIB_RGB_COLORS,
+                SRCCOPY,
+            );
+        }
+        let lines = match &state.current_session_info {
+            Some(session_info) => build_lines(&config, session_info),
+            None => ["Nothing playing".to_owned(), String::new(), String::new()],
+        };
+        drop(state);
+        drop(config);
+        SelectObject(hdc, GetStockObject(DEFAULT_GUI_FONT));
+        SetBkMode(hdc, TRANSPARENT);
+        let text_left = MINI_PLAYER_PADDING * 2 + art_size;
+        for (i, line) in lines.iter().enumerate() {
+            let mut text = line.encode_utf16().collect::<Vec<_>>();
+            let mut text_rect = RECT {
+                left: text_left,
+                top: MINI_PLAYER_PADDING + 4 + 20 * i as i32,
+                right: client_rect.right - MINI_PLAYER_PADDING,
+                bottom: MINI_PLAYER_PADDING + 24 + 20 * i as i32,
+            };
+            DrawTextW(hdc, &mut text, &mut text_rect, DT_SINGLELINE | DT_END_ELLIPSIS | DT_NOPREFIX);
+        }
+        let _ = EndPaint(hwnd, &paint_struct);
+    }
+}
+
+extern "system" fn mini_player_wndproc(hwnd: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    unsafe {
+        let mini_player_window_data_ptr = GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *mut MiniPlayerWindowData;
+        match message {
+            WM_PAINT if !mini_player_window_data_ptr.is_null() => {
+                paint_mini_player(hwnd, &*mini_player_window_data_ptr);
+                LRESULT(0)
+            }
+            // The window has no caption, so dragging anywhere on it moves it
+            WM_NCHITTEST => LRESULT(HTCAPTION as _),
+            WM_EXITSIZEMOVE if !mini_player_window_data_ptr.is_null() => {
+                let mini_player_window_data = &*mini_player_window_data_ptr;
+                let mut window_rect = RECT::default();
+                if GetWindowRect(hwnd, &mut window_rect).is_ok() {
+                    mini_player_window_data.config.write().unwrap().mini_player_position = Some((window_rect.left, window_rect.top));
+                    let _ = mini_player_window_data.event_tx.send(Event::ConfigChanged);
+                }
+                LRESULT(0)
+            }
+            WM_DESTROY => {
+                if !mini_player_window_data_ptr.is_null() {
+                    SetWindowLongPtrW(hwnd, GWLP_USERDATA, 0);
+                    drop(Box::from_raw(mini_player_window_data_ptr));
+                }
+                LRESULT(0)
+            }
+            _ => DefWindowProcW(hwnd, message, wparam, lparam),
+        }
+    }
+}
+
+fn open_mini_player_window(config: Arc<RwLock<Config>>, state: Arc<RwLock<State>>, event_tx: UnboundedSender<Event>) -> anyhow::Result<HWND> {
+    let (x, y) = match config.read().unwrap().mini_player_position {
+        Some(mini_player_position) => mini_player_position,
+        None => {
+            let work_area = get_monitor_work_areas().first().copied().ok_or(anyhow!("No monitors found"))?;
+            (
+                work_area.right - MINI_PLAYER_WIDTH - POPUP_MARGIN,
+                work_area.bottom - MINI_PLAYER_HEIGHT - POPUP_MARGIN,
+            )
         }
+    };
+    unsafe {
+        let instance = GetModuleHandleA(None)?;
+        let hwnd = CreateWindowExW(
+            WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+            MINI_PLAYER_WINDOW_CLASS,
+            windows_strings::w!("Now Playing"),
+            WS_POPUP | WS_BORDER,
+            x,
+            y,
+            MINI_PLAYER_WIDTH,
+            MINI_PLAYER_HEIGHT,
+            None,
+            None,
+            Some(instance.into()),
+            None,
+        )
+        .context("Can not create mini player window")?;
+        let mini_player_window_data = MiniPlayerWindowData { config, state, event_tx };
+        SetWindowLongPtrW(hwnd, GWLP_USERDATA, Box::into_raw(Box::new(mini_player_window_data)) as _);
+        let _ = ShowWindow(hwnd, SW_SHOWNOACTIVATE);
+        Ok(hwnd)
     }
 }
 
-fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::UnboundedSender<Event>) -> anyhow::Result<()> {
-    enable_dark_mode();
+fn windows_thread(
+    config_path: PathBuf,
+    config: Arc<RwLock<Config>>,
+    state: Arc<RwLock<State>>,
+    event_tx: tokio::sync::mpsc::UnboundedSender<Event>,
+) -> anyhow::Result<()> {
+    if let Err(e) = enable_dark_mode() {
+        log(format!("Unable to enable dark mode: {e:?}"))
+    }
 
     const ID_TRAY_EXIT: usize = 1001;
     const ID_TRAY_CLEAR_KNOWN: usize = 1002;
     const ID_TRAY_SEPARATOR: usize = 1003;
-    const ID_TRAY_SOURCES_START: usize = 1004;
+    const ID_TRAY_EXCLUDE_CURRENT: usize = 1004;
+    const ID_TRAY_SETTINGS: usize = 1005;
+    const ID_TRAY_COPY_CURRENT: usize = 1006;
+    const ID_TRAY_COPY_CURRENT_MARKDOWN: usize = 1007;
+    const ID_TRAY_MINI_PLAYER: usize = 1008;
+    const ID_TRAY_SOURCES_START: usize = 2000;
+    const ID_TRAY_SNOOZE_START: usize = 3000;
+    const ID_TRAY_PROFILES_START: usize = 4000;
     const WM_TRAYICON: u32 = WM_USER + 1;
+    const ID_TRAY_TOOLTIP_TIMER: usize = 1;
+    const TRAY_TOOLTIP: &str = "Now playing";
 
     let old_sources_count = Rc::new(Cell::<Option<usize>>::new(None));
+    let old_snooze_menu = Rc::new(Cell::<Option<HMENU>>::new(None));
+    let old_profiles_menu = Rc::new(Cell::<Option<HMENU>>::new(None));
 
     let update_menu = {
+        let config_path = config_path.clone();
         let config = config.clone();
+        let state = state.clone();
         move |hmenu: HMENU| -> anyhow::Result<()> {
             unsafe {
                 if let Some(old_sources_count) = old_sources_count.get() {
@@ -408,22 +4293,108 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
                         DeleteMenu(hmenu, (ID_TRAY_SOURCES_START + i) as _, MF_BYCOMMAND).context("Removing source item")?;
                     }
                     DeleteMenu(hmenu, ID_TRAY_SEPARATOR as _, MF_BYCOMMAND).context("Removing generic item")?;
+                    // Deleting the popup item also destroys the submenu
+                    if let Some(old_snooze_menu) = old_snooze_menu.get() {
+                        DeleteMenu(hmenu, old_snooze_menu.0 as _, MF_BYCOMMAND).context("Removing snooze menu")?;
+                    }
+                    if let Some(old_profiles_menu) = old_profiles_menu.get() {
+                        DeleteMenu(hmenu, old_profiles_menu.0 as _, MF_BYCOMMAND).context("Removing profiles menu")?;
+                    }
+                    DeleteMenu(hmenu, ID_TRAY_COPY_CURRENT as _, MF_BYCOMMAND).context("Removing generic item")?;
+                    DeleteMenu(hmenu, ID_TRAY_COPY_CURRENT_MARKDOWN as _, MF_BYCOMMAND).context("Removing generic item")?;
+                    DeleteMenu(hmenu, ID_TRAY_EXCLUDE_CURRENT as _, MF_BYCOMMAND).context("Removing generic item")?;
+                    DeleteMenu(hmenu, ID_TRAY_MINI_PLAYER as _, MF_BYCOMMAND).context("Removing generic item")?;
+                    DeleteMenu(hmenu, ID_TRAY_SETTINGS as _, MF_BYCOMMAND).context("Removing generic item")?;
                     DeleteMenu(hmenu, ID_TRAY_CLEAR_KNOWN as _, MF_BYCOMMAND).context("Removing generic item")?;
                     DeleteMenu(hmenu, ID_TRAY_EXIT as _, MF_BYCOMMAND).context("Removing generic item")?;
                 }
-                let sources = &config.read().unwrap().sources;
-                for (i, (source, enabled)) in sources.iter().enumerate() {
-                    AppendMenuA(
+                let config = config.read().unwrap();
+                let sources = &config.sources;
+                let state = state.read().unwrap();
+                let listening_times = &state.listening_times;
+                for (i, source) in sources.iter().enumerate() {
+                    let mut label = match listening_times.get(&source.id).map(ListeningTime::today) {
+                        Some(today) if config.show_listening_time && !today.is_zero() => format!("{} ({} today)", source.id, format_listening_time(today)),
+                        _ => source.id.clone(),
+                    };
+                    if let Some(remaining) = state.snooze_remaining(&source.id) {
+                        label += &format!(" (snoozed {})", format_listening_time(remaining + Duration::from_secs(59)));
+                    }
+                    AppendMenuW(
                         hmenu,
-                        MF_STRING | (if *enabled { MF_CHECKED } else { MF_UNCHECKED }),
+                        MF_STRING | (if source.enabled { MF_CHECKED } else { MF_UNCHECKED }),
                         ID_TRAY_SOURCES_START + i,
-                        PCSTR::from_raw(CString::new(&**source)?.as_ptr() as *const u8),
+                        &HSTRING::from(label),
                     )
                     .context("Adding source item")?;
                 }
-                AppendMenuA(hmenu, MF_SEPARATOR, ID_TRAY_SEPARATOR, PCSTR::null()).context("Adding generic item")?;
-                AppendMenuA(hmenu, MF_STRING, ID_TRAY_CLEAR_KNOWN, windows_strings::s!("Clear known")).context("Adding generic item")?;
-                AppendMenuA(hmenu, MF_STRING, ID_TRAY_EXIT, windows_strings::s!("Exit")).context("Adding generic item")?;
+                AppendMenuW(hmenu, MF_SEPARATOR, ID_TRAY_SEPARATOR, PCWSTR::null()).context("Adding generic item")?;
+                let snooze_menu = CreatePopupMenu().context("Creating snooze menu")?;
+                for (i, source) in sources.iter().enumerate() {
+                    AppendMenuW(
+                        snooze_menu,
+                        MF_STRING
+                            | (if state.snooze_remaining(&source.id).is_some() {
+                                MF_CHECKED
+                            } else {
+                                MF_UNCHECKED
+                            }),
+                        ID_TRAY_SNOOZE_START + i,
+                        &HSTRING::from(source.id.as_str()),
+                    )
+                    .context("Adding snooze item")?;
+                }
+                AppendMenuW(
+                    hmenu,
+                    MF_POPUP | (if sources.is_empty() { MF_GRAYED } else { MF_ENABLED }),
+                    snooze_menu.0 as _,
+                    &HSTRING::from(format!("Snooze for {} minutes", config.snooze_minutes)),
+                )
+                .context("Adding snooze menu")?;
+                old_snooze_menu.set(Some(snooze_menu));
+                let profiles_menu = CreatePopupMenu().context("Creating profiles menu")?;
+                for (i, profile) in std::iter::once(None).chain(list_profiles(&config_path).into_iter().map(Some)).enumerate() {
+                    AppendMenuW(
+                        profiles_menu,
+                        MF_STRING | (if state.active_profile == profile { MF_CHECKED } else { MF_UNCHECKED }),
+                        ID_TRAY_PROFILES_START + i,
+                        &HSTRING::from(profile.as_deref().unwrap_or("Default")),
+                    )
+                    .context("Adding profile item")?;
+                }
+                AppendMenuW(hmenu, MF_POPUP, profiles_menu.0 as _, windows_strings::w!("Profile")).context("Adding profiles menu")?;
+                old_profiles_menu.set(Some(profiles_menu));
+                AppendMenuW(
+                    hmenu,
+                    MF_STRING | (if state.current_session_info.is_some() { MF_ENABLED } else { MF_GRAYED }),
+                    ID_TRAY_COPY_CURRENT,
+                    windows_strings::w!("Copy current track"),
+                )
+                .context("Adding generic item")?;
+                AppendMenuW(
+                    hmenu,
+                    MF_STRING | (if state.current_session_info.is_some() { MF_ENABLED } else { MF_GRAYED }),
+                    ID_TRAY_COPY_CURRENT_MARKDOWN,
+                    windows_strings::w!("Copy current track as Markdown"),
+                )
+                .context("Adding generic item")?;
+                AppendMenuW(
+                    hmenu,
+                    MF_STRING | (if state.current_session_info.is_some() { MF_ENABLED } else { MF_GRAYED }),
+                    ID_TRAY_EXCLUDE_CURRENT,
+                    windows_strings::w!("Exclude current title"),
+                )
+                .context("Adding generic item")?;
+                AppendMenuW(
+                    hmenu,
+                    MF_STRING | (if config.mini_player { MF_CHECKED } else { MF_UNCHECKED }),
+                    ID_TRAY_MINI_PLAYER,
+                    windows_strings::w!("Mini player"),
+                )
+                .context("Adding generic item")?;
+                AppendMenuW(hmenu, MF_STRING, ID_TRAY_SETTINGS, windows_strings::w!("Settings...")).context("Adding generic item")?;
+                AppendMenuW(hmenu, MF_STRING, ID_TRAY_CLEAR_KNOWN, windows_strings::w!("Clear known")).context("Adding generic item")?;
+                AppendMenuW(hmenu, MF_STRING, ID_TRAY_EXIT, windows_strings::w!("Exit")).context("Adding generic item")?;
                 old_sources_count.set(Some(sources.len()));
             }
             Ok(())
@@ -431,11 +4402,44 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
     };
 
     struct WndprocData {
+        config_path: PathBuf,
         config: Arc<RwLock<Config>>,
-        nid: NOTIFYICONDATAA,
+        state: Arc<RwLock<State>>,
+        nid: NOTIFYICONDATAW,
+        tooltip: RefCell<String>,
         hmenu: HMENU,
         event_tx: tokio::sync::mpsc::UnboundedSender<Event>,
         update_menu: Box<dyn Fn(HMENU) -> anyhow::Result<()>>,
+        mini_player_hwnd: Cell<Option<HWND>>,
+        mini_player_revision: Cell<u64>,
+    }
+
+    fn sync_mini_player(wndproc_data: &WndprocData) -> anyhow::Result<()> {
+        let mini_player = wndproc_data.config.read().unwrap().mini_player;
+        match (mini_player, wndproc_data.mini_player_hwnd.get()) {
+            (true, None) => {
+                let mini_player_hwnd = open_mini_player_window(wndproc_data.config.clone(), wndproc_data.state.clone(), wndproc_data.event_tx.clone())?;
+                wndproc_data.mini_player_hwnd.set(Some(mini_player_hwnd));
+                wndproc_data.mini_player_revision.set(wndproc_data.state.read().unwrap().mini_player_revision);
+                wndproc_data.state.write().unwrap().mini_player_open = true;
+                wndproc_data.event_tx.send(Event::RefreshMiniPlayerArt)?;
+            }
+            (true, Some(mini_player_hwnd)) => {
+                let mini_player_revision = wndproc_data.state.read().unwrap().mini_player_revision;
+                if wndproc_data.mini_player_revision.replace(mini_player_revision) != mini_player_revision {
+                    let _ = unsafe { InvalidateRect(Some(mini_player_hwnd), None, true) };
+                }
+            }
+            (false, Some(mini_player_hwnd)) => {
+                unsafe { DestroyWindow(mini_player_hwnd) }.context("Can not destroy mini player window")?;
+                wndproc_data.mini_player_hwnd.set(None);
+                let mut state = wndproc_data.state.write().unwrap();
+                state.mini_player_open = false;
+                state.mini_player_art = None;
+            }
+            (false, None) => (),
+        }
+        Ok(())
     }
 
     extern "system" fn wndproc(hwnd: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
@@ -446,24 +4450,35 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
             let aux = || -> anyhow::Result<LRESULT> {
                 match message {
                     WM_TRAYICON => {
+                        if lparam.0 == WM_LBUTTONUP as isize {
+                            let left_click_action = wndproc_data.unwrap().config.read().unwrap().left_click_action;
+                            match left_click_action {
+                                LeftClickAction::Nothing => (),
+                                LeftClickAction::ShowCurrentTrack => wndproc_data.unwrap().event_tx.send(Event::ShowCurrentTrack)?,
+                                LeftClickAction::TogglePauseAll => wndproc_data.unwrap().event_tx.send(Event::TogglePauseAll)?,
+                                LeftClickAction::OpenSettings => {
+                                    open_settings_window(wndproc_data.unwrap().config.clone(), wndproc_data.unwrap().event_tx.clone())?;
+                                }
+                            }
+                        }
                         if lparam.0 == WM_RBUTTONUP as isize {
                             let mut pt = Default::default();
                             GetCursorPos(&mut pt)?;
-                            if !SetForegroundWindow(hwnd).as_bool() {
-                                eprintln!("Unable to set foreground window")
-                            } else {
-                                (wndproc_data.unwrap().update_menu)(wndproc_data.unwrap().hmenu)?;
-                                if !TrackPopupMenu(wndproc_data.unwrap().hmenu, TPM_RIGHTBUTTON, pt.x, pt.y, None, hwnd, None).as_bool() {
-                                    eprintln!("Unable to track popup menu")
-                                }
+                            if !force_foreground_window(hwnd) {
+                                log("Unable to set foreground window, the menu may not close when clicking elsewhere")
                             }
+                            (wndproc_data.unwrap().update_menu)(wndproc_data.unwrap().hmenu)?;
+                            if !TrackPopupMenu(wndproc_data.unwrap().hmenu, TPM_RIGHTBUTTON, pt.x, pt.y, None, hwnd, None).as_bool() {
+                                log("Unable to track popup menu")
+                            }
+                            PostMessageA(Some(hwnd), WM_NULL, WPARAM(0), LPARAM(0))?;
                         }
                         Ok(LRESULT(0))
                     }
                     WM_COMMAND => {
                         match wparam.0 {
                             ID_TRAY_EXIT => {
-                                if !Shell_NotifyIconA(NIM_DELETE, &wndproc_data.unwrap().nid).as_bool() {
+                                if !Shell_NotifyIconW(NIM_DELETE, &wndproc_data.unwrap().nid).as_bool() {
                                     bail!("Unable to notify icon")
                                 }
                                 PostQuitMessage(0);
@@ -474,11 +4489,74 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
                                 sources.clear();
                                 wndproc_data.unwrap().event_tx.send(Event::ConfigChanged)?;
                             }
+                            ID_TRAY_MINI_PLAYER => {
+                                {
+                                    let mut config = wndproc_data.unwrap().config.write().unwrap();
+                                    config.mini_player = !config.mini_player;
+                                }
+                                wndproc_data.unwrap().event_tx.send(Event::ConfigChanged)?;
+                                if let Err(e) = sync_mini_player(wndproc_data.unwrap()) {
+                                    log(format!("Unable to toggle mini player: {e:?}"))
+                                }
+                            }
+                            ID_TRAY_SETTINGS => {
+                                open_settings_window(wndproc_data.unwrap().config.clone(), wndproc_data.unwrap().event_tx.clone())?;
+                            }
+                            ID_TRAY_COPY_CURRENT => {
+                                if let Some(session_info) = &wndproc_data.unwrap().state.read().unwrap().current_session_info {
+                                    let text = build_clipboard_text(&wndproc_data.unwrap().config.read().unwrap(), session_info);
+                                    if let Err(e) = set_clipboard_text(hwnd, &text) {
+                                        log(format!("Unable to copy current track: {e:?}"))
+                                    }
+                                }
+                            }
+                            ID_TRAY_COPY_CURRENT_MARKDOWN => {
+                                if let Some(session_info) = &wndproc_data.unwrap().state.read().unwrap().current_session_info {
+                                    let text = build_markdown_clipboard_text(&wndproc_data.unwrap().config.read().unwrap(), session_info);
+                                    if let Err(e) = set_clipboard_text(hwnd, &text) {
+                                        log(format!("Unable to copy current track: {e:?}"))
+                                    }
+                                }
+                            }
+                            ID_TRAY_EXCLUDE_CURRENT => {
+                                if let Some(session_info) = &wndproc_data.unwrap().state.read().unwrap().current_session_info {
+                                    let exclude_patterns = &mut wndproc_data.unwrap().config.write().unwrap().exclude_patterns;
+                                    if !exclude_patterns.contains(&session_info.title) {
+                                        exclude_patterns.push(session_info.title.clone());
+                                    }
+                                    wndproc_data.unwrap().event_tx.send(Event::ConfigChanged)?;
+                                }
+                            }
+                            j if j >= ID_TRAY_PROFILES_START => {
+                                let i = j - ID_TRAY_PROFILES_START;
+                                let profile = match i {
+                                    0 => None,
+                                    i => match list_profiles(&wndproc_data.unwrap().config_path).into_iter().nth(i - 1) {
+                                        Some(profile) => Some(profile),
+                                        None => return Ok(LRESULT(0)),
+                                    },
+                                };
+                                wndproc_data.unwrap().event_tx.send(Event::SwitchProfile(profile))?;
+                            }
+                            j if j >= ID_TRAY_SNOOZE_START => {
+                                let i = j - ID_TRAY_SNOOZE_START;
+                                let snooze_minutes = wndproc_data.unwrap().config.read().unwrap().snooze_minutes;
+                                if let Some(source) = wndproc_data.unwrap().config.read().unwrap().sources.get(i) {
+                                    let mut state = wndproc_data.unwrap().state.write().unwrap();
+                                    if state.snooze_remaining(&source.id).is_some() {
+                                        state.snoozed_until.remove(&source.id);
+                                    } else {
+                                        state
+                                            .snoozed_until
+                                            .insert(source.id.clone(), Instant::now() + Duration::from_secs(snooze_minutes * 60));
+                                    }
+                                }
+                            }
                             j if j >= ID_TRAY_SOURCES_START => {
                                 let i = j - ID_TRAY_SOURCES_START;
                                 let sources = &mut wndproc_data.unwrap().config.write().unwrap().sources;
-                                if let Some((_, enabled)) = sources.get_mut(i) {
-                                    *enabled = !*enabled;
+                                if let Some(source) = sources.get_mut(i) {
+                                    source.enabled = !source.enabled;
                                 }
                                 wndproc_data.unwrap().event_tx.send(Event::ConfigChanged)?;
                             }
@@ -486,6 +4564,35 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
                         }
                         Ok(LRESULT(0))
                     }
+                    // Sessions can change while the machine sleeps without any event firing, so re-sync on resume
+                    WM_POWERBROADCAST => {
+                        if wparam.0 == PBT_APMRESUMEAUTOMATIC as usize {
+                            wndproc_data.unwrap().event_tx.send(Event::Update)?;
+                        }
+                        Ok(LRESULT(1))
+                    }
+                    WM_TIMER if wparam.0 == ID_TRAY_TOOLTIP_TIMER => {
+                        let wndproc_data = wndproc_data.unwrap();
+                        let mut tooltip = TRAY_TOOLTIP.to_owned();
+                        if wndproc_data.config.read().unwrap().show_playing_time
+                            && let Some(position) = wndproc_data.state.read().unwrap().current_session_info.as_ref().and_then(current_position)
+                        {
+                            tooltip += &format!(" (playing {})", format_position(position));
+                        }
+                        if *wndproc_data.tooltip.borrow() != tooltip {
+                            let mut nid = wndproc_data.nid;
+                            nid.uFlags = NIF_TIP;
+                            nid.szTip = tray_tip(&tooltip);
+                            if !Shell_NotifyIconW(NIM_MODIFY, &nid).as_bool() {
+                                log("Unable to update tray tooltip")
+                            }
+                            *wndproc_data.tooltip.borrow_mut() = tooltip;
+                        }
+                        if let Err(e) = sync_mini_player(wndproc_data) {
+                            log_rate_limited("mini_player", format!("Unable to update mini player: {e:?}"))
+                        }
+                        Ok(LRESULT(0))
+                    }
                     WM_DESTROY => {
                         PostQuitMessage(0);
                         wndproc_data.unwrap().event_tx.send(Event::Quit)?;
@@ -514,6 +4621,31 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
         let atom = RegisterClassA(&wc);
         assert!(atom != 0);
 
+        let settings_wc = WNDCLASSW {
+            hCursor: LoadCursorW(None, IDC_ARROW)?,
+            hInstance: instance.into(),
+            hbrBackground: GetSysColorBrush(COLOR_BTNFACE),
+            lpszClassName: SETTINGS_WINDOW_CLASS,
+            style: CS_HREDRAW,
+            lpfnWndProc: Some(settings_wndproc),
+            ..Default::default()
+        };
+
+        let settings_atom = RegisterClassW(&settings_wc);
+        assert!(settings_atom != 0);
+
+        let mini_player_wc = WNDCLASSW {
+            hCursor: LoadCursorW(None, IDC_ARROW)?,
+            hInstance: instance.into(),
+            hbrBackground: GetSysColorBrush(COLOR_BTNFACE),
+            lpszClassName: MINI_PLAYER_WINDOW_CLASS,
+            lpfnWndProc: Some(mini_player_wndproc),
+            ..Default::default()
+        };
+
+        let mini_player_atom = RegisterClassW(&mini_player_wc);
+        assert!(mini_player_atom != 0);
+
         let hwnd = CreateWindowExA(
             WINDOW_EX_STYLE::default(),
             window_class,
@@ -531,39 +4663,46 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
 
         let hmenu = CreatePopupMenu()?;
 
-        let nid = NOTIFYICONDATAA {
-            cbSize: size_of::<NOTIFYICONDATAA>() as _,
+        let nid = NOTIFYICONDATAW {
+            cbSize: size_of::<NOTIFYICONDATAW>() as _,
             hWnd: hwnd,
             uID: 1,
             uCallbackMessage: WM_TRAYICON,
             uFlags: NIF_MESSAGE | NIF_ICON | NIF_TIP,
-            hIcon: LoadIconA(Some(instance.into()), windows_strings::s!("IDI_MAIN_ICON"))?,
-            szTip: b"Now playing\0"
-                .iter()
-                .map(|&b| b as i8)
-                .chain(std::iter::repeat(0))
-                .take(128)
-                .collect::<Vec<_>>()
-                .try_into()
-                .unwrap(),
+            hIcon: match LoadIconA(Some(instance.into()), windows_strings::s!("IDI_MAIN_ICON")) {
+                Ok(hicon) => hicon,
+                // Binaries built without the resource script have no icon, the tray still needs one
+                Err(e) => {
+                    log(format!("Unable to load the tray icon, using the default one: {e:?}"));
+                    LoadIconW(None, IDI_APPLICATION)?
+                }
+            },
+            szTip: tray_tip(TRAY_TOOLTIP),
             ..Default::default()
         };
 
-        if !Shell_NotifyIconA(NIM_ADD, &nid).as_bool() {
+        if !Shell_NotifyIconW(NIM_ADD, &nid).as_bool() {
             bail!("Unable to add shell icon")
         }
 
         let wndproc_data = WndprocData {
+            config_path,
             config: config.clone(),
+            state: state.clone(),
             nid,
+            tooltip: RefCell::new(TRAY_TOOLTIP.to_owned()),
             hmenu,
             event_tx,
             update_menu: Box::new(update_menu),
+            mini_player_hwnd: Cell::new(None),
+            mini_player_revision: Cell::new(0),
         };
         SetWindowLongPtrA(hwnd, GWLP_USERDATA, Box::leak(Box::new(wndproc_data)) as *mut _ as _);
+        SetTimer(Some(hwnd), ID_TRAY_TOOLTIP_TIMER, 1000, None);
 
         let mut message = MSG::default();
         while GetMessageA(&mut message, None, 0, 0).into() {
+            let _ = TranslateMessage(&message);
             DispatchMessageA(&message);
         }
 
@@ -571,53 +4710,903 @@ fn windows_thread(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::Unbo
     }
 }
 
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/Levitifox/now-playing/releases/latest";
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const APP_AUMID: &str = "Levitifox.NowPlaying";
+
+/// An unpackaged app needs its AUMID registered with a display name, or Windows drops its toasts
+fn register_app_aumid() -> anyhow::Result<()> {
+    let display_name = "Now Playing".encode_utf16().chain(std::iter::once(0)).collect::<Vec<_>>();
+    unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            &HSTRING::from(format!("Software\\Classes\\AppUserModelId\\{APP_AUMID}")),
+            windows_strings::w!("DisplayName"),
+            REG_SZ.0,
+            Some(display_name.as_ptr() as _),
+            (display_name.len() * size_of::<u16>()) as _,
+        )
+        .ok()
+        .context("Can not register AUMID")
+    }
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+async fn get_latest_release() -> anyhow::Result<Release> {
+    let http_client = HttpClient::new().context("Can not create http client")?;
+    // GitHub rejects API requests without a user agent
+    http_client
+        .DefaultRequestHeaders()
+        .context("Can not get request headers")?
+        .UserAgent()
+        .context("Can not get user agent header")?
+        .TryParseAdd(&concat!("now-playing/", env!("CARGO_PKG_VERSION")).into())
+        .context("Can not set user agent header")?;
+    let body = http_client
+        .GetStringAsync(&Uri::CreateUri(&LATEST_RELEASE_URL.into()).context("Can not create uri")?)
+        .context("Can not get latest release")?
+        .await
+        .context("Can not get latest release")?;
+    serde_json::from_str(&body.to_string_lossy()).context("Can not parse latest release")
+}
+
+async fn check_updates(config: Arc<RwLock<Config>>, event_tx: tokio::sync::mpsc::UnboundedSender<Event>) {
+    loop {
+        let (check_updates, last_update_check) = {
+            let config = config.read().unwrap();
+            (config.check_updates, config.last_update_check)
+        };
+        let now = unix_time_now();
+        if check_updates && now.saturating_sub(last_update_check) >= UPDATE_CHECK_INTERVAL.as_secs() {
+            config.write().unwrap().last_update_check = now;
+            let _ = event_tx.send(Event::ConfigChanged);
+            match get_latest_release().await {
+                Ok(release) if parse_version(&release.tag_name) > parse_version(env!("CARGO_PKG_VERSION")) => {
+                    let toast = {
+                        let mut config = config.write().unwrap();
+                        if config.notified_update_version.as_ref() == Some(&release.tag_name) {
+                            None
+                        } else {
+                            config.notified_update_version = Some(release.tag_name.clone());
+                            Some(Toast {
+                                launch_uri: Some(release.html_url.clone()),
+                                ..build_notice_toast(
+                                    &config,
+                                    APP_AUMID.to_owned(),
+                                    [
+                                        "Now Playing update available".to_owned(),
+                                        format!("Version {} is out, you have {}", release.tag_name, env!("CARGO_PKG_VERSION")),
+                                        "Click to open the release page".to_owned(),
+                                    ],
+                                )
+                            })
+                        }
+                    };
+                    if let Some(toast) = toast {
+                        let _ = event_tx.send(Event::ConfigChanged);
+                        if let Err(e) = register_app_aumid() {
+                            log(format!("Unable to register AUMID: {e:?}"));
+                        }
+                        if let Err(e) = send_toast(toast).await {
+                            log(format!("Unable to send update toast: {e:?}"));
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => log(format!("Unable to check for updates: {e:?}")),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+async fn quit_on_console_signal(event_tx: tokio::sync::mpsc::UnboundedSender<Event>) {
+    let aux = async || -> anyhow::Result<()> {
+        let mut ctrl_break = tokio::signal::windows::ctrl_break()?;
+        let mut ctrl_close = tokio::signal::windows::ctrl_close()?;
+        let mut ctrl_logoff = tokio::signal::windows::ctrl_logoff()?;
+        let mut ctrl_shutdown = tokio::signal::windows::ctrl_shutdown()?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = ctrl_break.recv() => (),
+            _ = ctrl_close.recv() => (),
+            _ = ctrl_logoff.recv() => (),
+            _ = ctrl_shutdown.recv() => (),
+        }
+        Ok(())
+    };
+    match aux().await {
+        Ok(()) => {
+            let _ = event_tx.send(Event::Quit);
+        }
+        Err(e) => log(format!("Unable to listen for console signals: {e:?}")),
+    }
+}
+
+async fn serve_health(config: Arc<RwLock<Config>>, state: Arc<RwLock<State>>) {
+    let started = Instant::now();
+    let Some(health_port) = config.read().unwrap().health_port else {
+        return;
+    };
+    let listener = match tokio::net::TcpListener::bind(("127.0.0.1", health_port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log(format!("Unable to listen on port {health_port}: {e:?}"));
+            return;
+        }
+    };
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log(format!("Unable to accept health connection: {e:?}"));
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut aux = async || -> anyhow::Result<()> {
+                let mut request = [0; 1024];
+                let len = tokio::io::AsyncReadExt::read(&mut stream, &mut request).await?;
+                let request = String::from_utf8_lossy(&request[..len]);
+                let response = if request.starts_with("GET /health ") {
+                    let body = {
+                        let state = state.read().unwrap();
+                        let health = &state.health;
+                        serde_json::json!({
+                            "uptime_secs": started.elapsed().as_secs(),
+                            "sessions_tracked": health.sessions_tracked,
+                            "toasts_shown": health.toasts_shown,
+                            "toasts_dropped": health.toasts_dropped,
+                            "last_error_unix_time": health.last_error_unix_time,
+                        })
+                        .to_string()
+                    };
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+                };
+                tokio::io::AsyncWriteExt::write_all(&mut stream, response.as_bytes()).await?;
+                Ok(())
+            };
+            if let Err(e) = aux().await {
+                log(format!("Unable to answer health request: {e:?}"))
+            }
+        });
+    }
+}
+
 #[derive(Debug, clap::Subcommand)]
 enum Command {
-    RunNotifier,
-    SendToast { toast_json_path: String },
+    RunNotifier {
+        /// Run without a tray icon, stop with Ctrl+C
+        #[clap(long)]
+        no_tray: bool,
+        /// Show the last N tracks of the history before waiting for new ones
+        #[clap(long, value_name = "N")]
+        replay: Option<usize>,
+        /// Never write the config back, changes from the tray only last for this run
+        #[clap(long)]
+        read_only_config: bool,
+    },
+    SendToast {
+        toast_json_path: String,
+        /// Print the toast XML that would be shown instead of showing it
+        #[clap(long)]
+        print_toast_xml: bool,
+    },
+    /// List media sessions; with `--verbose`, dump every field GSMTC reports
+    ListSessions {
+        #[clap(long)]
+        verbose: bool,
+    },
+    /// Print a shell completion script to stdout
+    Completions { shell: clap_complete::Shell },
+    /// Print the resolved config path and the effective config
+    ConfigInfo,
+    /// Check the config for syntax errors and suspicious values, exits with an error if any are found
+    CheckConfig,
+    /// Create the default config and start with Windows, safe to run again
+    Install,
+    /// Stop starting with Windows
+    Uninstall {
+        /// Also remove the config and its profiles
+        #[clap(long)]
+        remove_config: bool,
+    },
+    /// List the installed image decoders, exits with an error if a common thumbnail format has none
+    CheckCodecs,
+    /// Write the thumbnail of the current session to a file, exits with an error if there is none
+    DumpThumbnail { path: PathBuf },
+    /// Write play counts per source, artist and track from the history to a CSV file
+    ExportStats {
+        path: PathBuf,
+        /// Only count plays on or after this UTC date, YYYY-MM-DD
+        #[clap(long, value_parser = parse_date)]
+        since: Option<i64>,
+        /// Only count plays on or before this UTC date, YYYY-MM-DD
+        #[clap(long, value_parser = parse_date)]
+        until: Option<i64>,
+    },
+    /// Show the toasts another instance forwards with `forward_to`
+    ListenToasts {
+        #[clap(long, default_value = "127.0.0.1:7315")]
+        bind: SocketAddr,
+        /// Allow binding to an address other hosts can reach, any of them can then send toasts
+        #[clap(long)]
+        allow_remote: bool,
+    },
 }
 
 #[derive(Debug, clap::Parser)]
 struct Cli {
     #[clap(subcommand)]
     command: Option<Command>,
+    /// Config file to use instead of the `NOW_PLAYING_CONFIG` environment variable or the default location
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let command = cli.command.unwrap_or(Command::RunNotifier);
+    let command = cli.command.unwrap_or(Command::RunNotifier {
+        no_tray: false,
+        replay: None,
+        read_only_config: false,
+    });
     match command {
-        Command::RunNotifier => {
-            let config_path = ProjectDirs::from("xyz", "Levitifox", "Now Playing")
-                .ok_or(anyhow!("Unable to get config dir"))?
-                .config_dir()
-                .join("config.json");
-            let config = if let Ok(config_str) = fs::read_to_string(&config_path)
-                && let Ok(config) = serde_json::from_str::<Config>(&config_str)
-            {
-                config
-            } else {
-                Config { sources: vec![] }
-            };
+        Command::RunNotifier {
+            no_tray,
+            replay,
+            read_only_config,
+        } => {
+            let (config_path, _) = resolve_config_path(cli.config.as_deref())?;
+            let active_profile = read_active_profile(&config_path);
+            let config = load_config(&profile_config_path(&config_path, active_profile.as_deref())).unwrap_or_default();
             let config = Arc::new(RwLock::new(config));
+            let state = Arc::new(RwLock::new(State {
+                active_profile,
+                read_only_config,
+                ..Default::default()
+            }));
             let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
-            thread::spawn({
-                let event_tx = event_tx.clone();
-                {
-                    let config = config.clone();
-                    move || windows_thread(config, event_tx)
-                }
-            });
-            command_run_notifer(config_path, config.clone(), event_tx, event_rx)
+            let _run_temp_dir = create_run_temp_dir()?;
+            tokio::spawn(quit_on_console_signal(event_tx.clone()));
+            tokio::spawn(flush_rate_limited_logs());
+            tokio::spawn(check_updates(config.clone(), event_tx.clone()));
+            tokio::spawn(serve_health(config.clone(), state.clone()));
+            if no_tray {
+                attach_console();
+            } else {
+                thread::spawn({
+                    let event_tx = event_tx.clone();
+                    {
+                        let config_path = config_path.clone();
+                        let config = config.clone();
+                        let state = state.clone();
+                        move || windows_thread(config_path, config, state, event_tx)
+                    }
+                });
+            }
+            if let Some(replay) = replay {
+                replay_history(config.clone(), replay).await;
+            }
+            command_run_notifer(config_path, config.clone(), state, event_tx, event_rx)
                 .await
                 .context("Run notifier failed")?
         }
-        Command::SendToast { toast_json_path } => {
+        Command::SendToast {
+            toast_json_path,
+            print_toast_xml,
+        } => {
+            if let Some(run_temp_dir) = env::var_os(RUN_TEMP_DIR_ENV) {
+                let _ = RUN_TEMP_DIR.set(run_temp_dir.into());
+            }
             let toast_json = String::from_utf8(fs::read(toast_json_path)?)?;
             let toast = serde_json::from_str(&toast_json)?;
-            command_send_toast(toast).await.context("Send toast failed")?
+            command_send_toast(toast, print_toast_xml).await.context("Send toast failed")?
+        }
+        Command::ListSessions { verbose } => {
+            attach_console();
+            command_list_sessions(verbose).await.context("List sessions failed")?
+        }
+        Command::Completions { shell } => {
+            attach_console();
+            clap_complete::generate(shell, &mut Cli::command(), env!("CARGO_PKG_NAME"), &mut std::io::stdout());
+        }
+        Command::ConfigInfo => {
+            attach_console();
+            command_config_info(cli.config.as_deref()).context("Config info failed")?
+        }
+        Command::CheckConfig => {
+            attach_console();
+            command_check_config(cli.config.as_deref()).context("Check config failed")?
+        }
+        Command::Install => {
+            attach_console();
+            command_install(cli.config.as_deref()).context("Install failed")?
+        }
+        Command::Uninstall { remove_config } => {
+            attach_console();
+            command_uninstall(cli.config.as_deref(), remove_config).context("Uninstall failed")?
+        }
+        Command::ListenToasts { bind, allow_remote } => {
+            attach_console();
+            let _run_temp_dir = create_run_temp_dir()?;
+            command_listen_toasts(bind, allow_remote).await.context("Listen toasts failed")?
+        }
+        Command::ExportStats { path, since, until } => {
+            attach_console();
+            command_export_stats(cli.config.as_deref(), &path, since, until).context("Export stats failed")?
+        }
+        Command::CheckCodecs => {
+            attach_console();
+            command_check_codecs().context("Check codecs failed")?
+        }
+        Command::DumpThumbnail { path } => {
+            attach_console();
+            command_dump_thumbnail(&path).await.context("Dump thumbnail failed")?
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_session_info(source_app_user_mode_id: &str, title: &str, artist: &str) -> SessionInfo {
+        SessionInfo {
+            source_app_user_mode_id: source_app_user_mode_id.to_owned(),
+            title: title.to_owned(),
+            subtitle: String::new(),
+            artist: artist.to_owned(),
+            album_title: String::new(),
+            thumbnail: None,
+            playback_status: GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing,
+            playback_type: PlaybackType::Music,
+            duration: Some(Duration::from_secs(180)),
+            timeline_position: None,
+        }
+    }
+
+    fn xml_attribute(xml_document: &XmlDocument, tag_name: &str, attribute_name: &str) -> Option<String> {
+        let element = xml_document
+            .GetElementsByTagName(&tag_name.into())
+            .unwrap()
+            .into_iter()
+            .next()?
+            .cast::<XmlElement>()
+            .unwrap();
+        Some(element.GetAttribute(&attribute_name.into()).unwrap().to_string_lossy())
+    }
+
+    #[test]
+    fn toast_xml_parses_for_every_scenario_sound_and_style() {
+        let scenarios = [
+            ToastScenario::Default,
+            ToastScenario::Reminder,
+            ToastScenario::Alarm,
+            ToastScenario::IncomingCall,
+            ToastScenario::Urgent,
+        ];
+        let sounds = [
+            ToastSound::Silent,
+            ToastSound::Default,
+            ToastSound::Custom("ms-winsoundevent:Notification.Reminder".to_owned()),
+            ToastSound::Custom("ms-winsoundevent:Notification.Looping.Alarm".to_owned()),
+        ];
+        for ((scenario, sound), style) in scenarios
+            .into_iter()
+            .cartesian_product(&sounds)
+            .cartesian_product([ToastStyle::Compact, ToastStyle::Full])
+        {
+            let toast = Toast {
+                scenario,
+                sound: sound.clone(),
+                style,
+                ..build_toast(&Config::default(), &test_session_info("Test", "Title", "Artist"))
+            };
+            let xml_document = XmlDocument::new().unwrap();
+            xml_document.LoadXml(&build_toast_xml(&toast).unwrap().GetXml().unwrap()).unwrap();
+            assert_eq!(
+                xml_attribute(&xml_document, "binding", "template").as_deref(),
+                Some(match style {
+                    ToastStyle::Compact => "ToastText01",
+                    ToastStyle::Full => "ToastText04",
+                })
+            );
+            assert_eq!(
+                xml_attribute(&xml_document, "toast", "scenario").unwrap_or_default(),
+                scenario.attribute().unwrap_or_default()
+            );
+            assert_eq!(
+                xml_attribute(&xml_document, "action", "activationType").is_some(),
+                scenario.attribute().is_some()
+            );
+            match sound {
+                ToastSound::Silent => assert_eq!(xml_attribute(&xml_document, "audio", "silent").as_deref(), Some("true")),
+                ToastSound::Default => assert_eq!(xml_attribute(&xml_document, "audio", "src"), None),
+                ToastSound::Custom(src) => {
+                    assert_eq!(xml_attribute(&xml_document, "audio", "src").as_ref(), Some(src));
+                    let looping = src.contains(".Looping.") && matches!(scenario, ToastScenario::Alarm | ToastScenario::IncomingCall);
+                    assert_eq!(xml_attribute(&xml_document, "audio", "loop").as_deref(), looping.then_some("true"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_config_accepts_what_sanitize_produces() {
+        for (session_retry_attempts, session_retry_delay_ms) in [(0, 0), (1000, 100_000)] {
+            let mut config = Config {
+                session_retry_attempts,
+                session_retry_delay_ms,
+                ..Default::default()
+            };
+            let config_value = serde_json::to_value(&config).unwrap();
+            assert_eq!(check_config(&config_value, &config).len(), 2);
+            config.sanitize();
+            assert!(check_config(&config_value, &config).is_empty());
+        }
+    }
+
+    #[test]
+    fn prune_sources_keeps_customized_sources() {
+        let source = |id: &str, last_seen: u64| Source {
+            id: id.to_owned(),
+            enabled: true,
+            last_seen,
+            ..Default::default()
+        };
+        let mut sources = vec![
+            Source {
+                template: Some("{title}".to_owned()),
+                ..source("template", 0)
+            },
+            Source {
+                sound: Some(ToastSound::Default),
+                ..source("sound", 1)
+            },
+            Source {
+                enabled: false,
+                ..source("disabled", 1)
+            },
+            source("old", 2),
+            source("current", 3),
+            source("new", 4),
+        ];
+        prune_sources(&mut sources, 3, "current");
+        assert_eq!(
+            sources.iter().map(|source| source.id.as_str()).collect::<Vec<_>>(),
+            ["template", "sound", "disabled", "current"]
+        );
+    }
+
+    #[test]
+    fn rate_limited_log_summaries_are_taken_once_the_window_is_over() {
+        let start = Instant::now();
+        let mut rate_limited_log_entries = HashMap::from([
+            (
+                "failing".to_owned(),
+                RateLimitedLogEntry {
+                    window_start: start,
+                    suppressed: 3,
+                },
+            ),
+            (
+                "quiet".to_owned(),
+                RateLimitedLogEntry {
+                    window_start: start,
+                    suppressed: 0,
+                },
+            ),
+        ]);
+        assert!(take_rate_limited_log_summaries(&mut rate_limited_log_entries, start).is_empty());
+        assert_eq!(rate_limited_log_entries.len(), 2);
+        let summaries = take_rate_limited_log_summaries(&mut rate_limited_log_entries, start + LOG_RATE_LIMIT_WINDOW);
+        assert_eq!(summaries, vec!["failing: failed 3 more times in the last minute".to_owned()]);
+        assert!(rate_limited_log_entries.is_empty());
+    }
+
+    #[test]
+    fn is_lyrics_update_needs_repeated_title_changes_of_the_same_track() {
+        let mut title_changes = HashMap::new();
+        let line = |title: &str| test_session_info("Test", title, "Artist");
+        assert!(!is_lyrics_update(&mut title_changes, None, &line("Song")));
+        assert!(!is_lyrics_update(&mut title_changes, Some(&line("Song")), &line("First line")));
+        assert!(is_lyrics_update(&mut title_changes, Some(&line("First line")), &line("Second line")));
+        assert!(is_lyrics_update(&mut title_changes, Some(&line("Second line")), &line("Third line")));
+        let other_artist = test_session_info("Test", "Other song", "Other artist");
+        assert!(!is_lyrics_update(&mut title_changes, Some(&line("Third line")), &other_artist));
+        assert!(title_changes.is_empty());
+    }
+
+    #[test]
+    fn is_lyrics_update_lets_skips_within_an_album_through() {
+        let mut title_changes = HashMap::new();
+        let track = |title: &str, seconds: u64| SessionInfo {
+            album_title: "Album".to_owned(),
+            duration: Some(Duration::from_secs(seconds)),
+            ..test_session_info("Test", title, "Artist")
+        };
+        assert!(!is_lyrics_update(&mut title_changes, Some(&track("First", 200)), &track("Second", 180)));
+        assert!(!is_lyrics_update(&mut title_changes, Some(&track("Second", 180)), &track("Third", 240)));
+        assert!(!is_lyrics_update(&mut title_changes, Some(&track("Third", 240)), &track("Fourth", 210)));
+        let untimed = |title: &str| SessionInfo {
+            duration: None,
+            ..test_session_info("Test", title, "Artist")
+        };
+        assert!(!is_lyrics_update(&mut title_changes, Some(&untimed("First")), &untimed("Second")));
+        assert!(!is_lyrics_update(&mut title_changes, Some(&untimed("Second")), &untimed("Third")));
+    }
+
+    #[test]
+    fn render_template_keeps_unknown_placeholders() {
+        let session_info = SessionInfo {
+            album_title: "Album".to_owned(),
+            ..test_session_info("Test", "Title", "Artist")
+        };
+        assert_eq!(render_template("{title} by {artist}\n{album}", &session_info), "Title by Artist\nAlbum");
+        assert_eq!(render_template("{unknown} {title", &session_info), "{unknown} {title");
+        assert_eq!(render_template("{{title}}", &session_info), "{Title}");
+    }
+
+    #[test]
+    fn build_lines_prefers_the_source_template() {
+        let session_info = SessionInfo {
+            subtitle: "Subtitle".to_owned(),
+            album_title: "Album".to_owned(),
+            ..test_session_info("Test", "Title", "Artist")
+        };
+        let mut config = Config {
+            toast: ToastConfig {
+                title_subtitle_separator: Some(" – ".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(build_lines(&config, &session_info), ["Title – Subtitle", "Album", "Artist"]);
+        config.toast.template = Some("{title}\n{artist}\n{album}\nextra".to_owned());
+        assert_eq!(build_lines(&config, &session_info), ["Title", "Artist", "Album\nextra"]);
+        config.sources.push(Source {
+            id: "Test".to_owned(),
+            enabled: true,
+            template: Some("{artist} – {title}".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(build_lines(&config, &session_info), ["Artist – Title", "", ""]);
+    }
+
+    #[test]
+    fn resolve_config_path_prefers_the_override_then_the_environment() {
+        // Safety: no other test reads or writes `CONFIG_PATH_ENV`
+        unsafe { env::set_var(CONFIG_PATH_ENV, "from_env.json") };
+        let resolved = resolve_config_path(Some(Path::new("custom.json"))).unwrap();
+        assert_eq!(resolved, (PathBuf::from("custom.json"), "--config"));
+        let resolved = resolve_config_path(None).unwrap();
+        assert_eq!(resolved, (PathBuf::from("from_env.json"), CONFIG_PATH_ENV));
+        unsafe { env::remove_var(CONFIG_PATH_ENV) };
+    }
+
+    #[test]
+    fn split_and_coalesce_artists() {
+        assert_eq!(split_artists("A feat. B, C & c ; /"), ["A", "B", "C", "c"]);
+        assert_eq!(split_artists("Ünï / Çödé"), ["Ünï", "Çödé"]);
+        assert!(split_artists("").is_empty());
+        assert_eq!(coalesce_artists("A feat. B, C & c", ", "), "A, B, C");
+        assert_eq!(coalesce_artists("A", ", "), "A");
+    }
+
+    #[test]
+    fn separator_for_langid_follows_the_primary_language() {
+        assert_eq!(separator_for_langid(0x0411), "／");
+        assert_eq!(separator_for_langid(0x0419), " — ");
+        assert_eq!(separator_for_langid(0x0409), " – ");
+        assert_eq!(separator_for_langid(0x0809), " – ");
+    }
+
+    #[tokio::test]
+    async fn toast_rate_limiter_suppresses_past_the_limit() {
+        let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+        let toast = build_notice_toast(&Config::default(), "Test".to_owned(), [String::new(), String::new(), String::new()]);
+        let mut toast_rate_limiter = ToastRateLimiter::default();
+        assert!(toast_rate_limiter.admit(&toast, Some(2), &event_tx));
+        assert!(toast_rate_limiter.admit(&toast, Some(2), &event_tx));
+        assert!(!toast_rate_limiter.admit(&toast, Some(2), &event_tx));
+        assert!(!toast_rate_limiter.admit(&toast, Some(2), &event_tx));
+        assert_eq!(toast_rate_limiter.take_suppressed(Some(2)), None);
+        assert_eq!(toast_rate_limiter.take_suppressed(None), Some((2, "Test".to_owned())));
+        assert_eq!(toast_rate_limiter.take_suppressed(None), None);
+        assert!(ToastRateLimiter::default().admit(&toast, None, &event_tx));
+    }
+
+    #[test]
+    fn is_same_track_compares_by_dedup_key() {
+        let track = test_session_info("Test", "Title", "Artist");
+        let other_artist = test_session_info("Test", "Title", "Other artist");
+        let other_album = SessionInfo {
+            album_title: "Album".to_owned(),
+            ..track.clone()
+        };
+        let paused = SessionInfo {
+            playback_status: GlobalSystemMediaTransportControlsSessionPlaybackStatus::Paused,
+            ..track.clone()
+        };
+        assert!(is_same_track(DedupKey::Title, &track, &other_artist));
+        assert!(!is_same_track(DedupKey::TitleArtist, &track, &other_artist));
+        assert!(is_same_track(DedupKey::TitleArtist, &track, &other_album));
+        assert!(!is_same_track(DedupKey::Full, &track, &other_album));
+        assert!(is_same_track(DedupKey::Full, &track, &paused));
+        assert!(!is_same_track(DedupKey::Title, &track, &test_session_info("Other", "Title", "Artist")));
+    }
+
+    #[test]
+    fn build_lines_puts_the_artist_first_when_configured() {
+        let session_info = SessionInfo {
+            subtitle: "Subtitle".to_owned(),
+            album_title: "Album".to_owned(),
+            ..test_session_info("Test", "Title", "Artist")
+        };
+        let config = Config {
+            toast: ToastConfig {
+                title_subtitle_separator: Some(" - ".to_owned()),
+                name_order: NameOrder::ArtistFirst,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(build_lines(&config, &session_info), ["Artist", "Title - Subtitle", "Album"]);
+        let session_info = SessionInfo {
+            artist: String::new(),
+            ..session_info
+        };
+        assert_eq!(build_lines(&config, &session_info), ["Title - Subtitle", "Album", ""]);
+    }
+
+    #[test]
+    fn queue_toast_drops_and_counts_instead_of_waiting_when_full() {
+        let (toast_tx, mut toast_rx) = tokio::sync::mpsc::channel(1);
+        let state = RwLock::new(State::default());
+        let toast = build_notice_toast(&Config::default(), "Test".to_owned(), [String::new(), String::new(), String::new()]);
+        for _ in 0..3 {
+            queue_toast(&toast_tx, &state, toast.clone());
+        }
+        assert_eq!(state.read().unwrap().health.toasts_dropped, 2);
+        assert!(toast_rx.try_recv().is_ok());
+        assert!(toast_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn quit_is_received_while_toasts_are_queued() {
+        let (toast_tx, _toast_rx) = tokio::sync::mpsc::channel(1);
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = RwLock::new(State::default());
+        for i in 0..100 {
+            queue_toast(
+                &toast_tx,
+                &state,
+                Toast {
+                    source_app_user_mode_id: format!("Test {i}"),
+                    ..build_toast(&Config::default(), &test_session_info("Test", "Title", "Artist"))
+                },
+            );
+        }
+        event_tx.send(Event::Quit).unwrap();
+        let event = tokio::time::timeout(Duration::from_millis(100), event_rx.recv()).await.unwrap();
+        assert_eq!(event, Some(Event::Quit));
+    }
+
+    #[test]
+    fn build_markdown_clipboard_text_links_the_title_when_possible() {
+        let config = Config::default();
+        let session_info = SessionInfo {
+            album_title: "Album".to_owned(),
+            ..test_session_info("Spotify.exe", "Title", "Artist")
+        };
+        assert_eq!(
+            build_markdown_clipboard_text(&config, &session_info),
+            "**[Title](spotify:search:Title%20Artist)** – Artist – Album"
+        );
+        let session_info = test_session_info("Test", "A*B", "Artist");
+        assert_eq!(build_markdown_clipboard_text(&config, &session_info), "**A\\*B** – Artist");
+    }
+
+    #[test]
+    fn build_lines_hides_an_album_named_like_the_title() {
+        let session_info = SessionInfo {
+            album_title: " title".to_owned(),
+            ..test_session_info("Test", "Title", "Artist")
+        };
+        let config = Config {
+            toast: ToastConfig {
+                title_subtitle_separator: Some(" - ".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(build_lines(&config, &session_info), ["Title", " title", "Artist"]);
+        let config = Config {
+            hide_redundant_album: true,
+            ..config
+        };
+        assert_eq!(build_lines(&config, &session_info), ["Title", "Artist", ""]);
+    }
+
+    #[test]
+    fn tray_tip_truncates_to_fit_with_a_terminator() {
+        let tip = tray_tip(&"a".repeat(200));
+        assert!(tip[..127].iter().all(|&c| c == u16::from(b'a')));
+        assert_eq!(tip[127], 0);
+    }
+
+    #[test]
+    fn tray_tip_does_not_split_surrogate_pairs() {
+        let tip = tray_tip(&format!("{}😀", "a".repeat(126)));
+        assert_eq!(tip[125], u16::from(b'a'));
+        assert_eq!(tip[126], 0);
+        let tip = tray_tip("Ünïcödé 😀");
+        assert_eq!(String::from_utf16(&tip[..tip.iter().position(|&c| c == 0).unwrap()]).unwrap(), "Ünïcödé 😀");
+    }
+
+    #[test]
+    fn limit_artists_dedupes_and_counts_the_rest() {
+        assert_eq!(limit_artists("A, B, C", ", ", 2), "A, B & 1 more");
+        assert_eq!(limit_artists("A, B, C", ", ", 0), "A & 2 more");
+        assert_eq!(limit_artists("A & B", ", ", 2), "A, B");
+        assert_eq!(limit_artists("A, a, B", ", ", 2), "A, B");
+    }
+
+    #[test]
+    fn sanitize_forwarded_toast_drops_local_only_fields() {
+        let toast = Toast {
+            launch_uri: Some("ms-settings:".to_owned()),
+            custom_xml: Some("<toast/>".to_owned()),
+            monitor: Some(1),
+            link_uri: Some("file:///C:/Windows".to_owned()),
+            sound: ToastSound::Custom("C:\\Windows\\Media\\tada.wav".to_owned()),
+            ..build_toast(&Config::default(), &test_session_info("Test", "Title", "Artist"))
+        };
+        let toast = sanitize_forwarded_toast(toast);
+        assert_eq!(toast.launch_uri, None);
+        assert_eq!(toast.custom_xml, None);
+        assert_eq!(toast.monitor, None);
+        assert_eq!(toast.link_uri, None);
+        assert_eq!(toast.sound, ToastSound::Silent);
+        let toast = Toast {
+            launch_uri: Some("HTTPS://example.com".to_owned()),
+            ..toast
+        };
+        assert_eq!(sanitize_forwarded_toast(toast).launch_uri.as_deref(), Some("HTTPS://example.com"));
+    }
+
+    #[tokio::test]
+    async fn session_churn_guard_backs_off_from_churning_sources() {
+        let (event_tx, _event_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut session_churn_guard = SessionChurnGuard::default();
+        let session_info = test_session_info("Test", "Title", "Artist");
+        for _ in 0..2 {
+            let session_infos = session_churn_guard.filter(vec![session_info.clone()], &[], Some(2), &event_tx);
+            assert_eq!(session_infos, std::slice::from_ref(&session_info));
+        }
+        assert!(session_churn_guard.filter(vec![session_info.clone()], &[], Some(2), &event_tx).is_empty());
+        let prev_session_info = test_session_info("Test", "Previous", "Artist");
+        let session_infos = session_churn_guard.filter(vec![session_info.clone()], std::slice::from_ref(&prev_session_info), Some(2), &event_tx);
+        assert_eq!(session_infos, [prev_session_info]);
+        let other = test_session_info("Other", "Title", "Artist");
+        assert_eq!(session_churn_guard.filter(vec![other.clone()], &[], Some(2), &event_tx), [other]);
+        let session_infos = session_churn_guard.filter(vec![session_info.clone()], &[], None, &event_tx);
+        assert_eq!(session_infos, [session_info]);
+    }
+
+    #[test]
+    fn parse_date_counts_days_since_the_epoch() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 19782);
+        assert_eq!(parse_date("1969-12-31").unwrap(), -1);
+        for date in ["2023-02-29", "2024-13-01", "2024-01", "2024-01-xx"] {
+            assert!(parse_date(date).is_err(), "{date}");
+        }
+    }
+
+    #[test]
+    fn build_stats_csv_counts_sources_artists_and_tracks() {
+        let history_entry = |source_app_user_mode_id: &'static str, artist: &'static str, title: &'static str| HistoryEntry {
+            time: String::new(),
+            unix_time: 0,
+            source_app_user_mode_id: source_app_user_mode_id.into(),
+            title: title.into(),
+            subtitle: "".into(),
+            artist: artist.into(),
+            album_title: "".into(),
+        };
+        assert_eq!(build_stats_csv(&[]), "kind,source,artist,title,plays\n");
+        let history_entries = [
+            history_entry("Spotify", "Artist", "Title"),
+            history_entry("Spotify", "Artist", "Title"),
+            history_entry("Spotify", "Other, Artist", "Other"),
+            history_entry("Browser", "", "Video"),
+        ];
+        assert_eq!(
+            build_stats_csv(&history_entries),
+            "kind,source,artist,title,plays\n\
+             source,Spotify,,,3\n\
+             source,Browser,,,1\n\
+             artist,,Artist,,2\n\
+             artist,,\"Other, Artist\",,1\n\
+             track,,Artist,Title,2\n\
+             track,,,Video,1\n\
+             track,,\"Other, Artist\",Other,1\n"
+        );
+    }
+
+    #[test]
+    fn merge_title_subtitle_drops_repeated_and_joins_continued_subtitles() {
+        assert_eq!(merge_title_subtitle("Song", "").as_deref(), Some("Song"));
+        assert_eq!(merge_title_subtitle("Song (Live)", "live").as_deref(), Some("Song (Live)"));
+        assert_eq!(merge_title_subtitle("Song", "Song (Remastered)").as_deref(), Some("Song (Remastered)"));
+        assert_eq!(merge_title_subtitle("A very long…", "title").as_deref(), Some("A very long title"));
+        assert_eq!(merge_title_subtitle("Episode 1:", "The Start").as_deref(), Some("Episode 1: The Start"));
+        assert_eq!(merge_title_subtitle("Song", "continued").as_deref(), Some("Song continued"));
+        assert_eq!(merge_title_subtitle("Song", "Artist"), None);
+    }
+
+    #[test]
+    fn migrate_config_value_moves_flat_toast_fields() {
+        let mut config_value = serde_json::json!({
+            "toast_duration_ms": 5000,
+            "toast_gap_ms": 1,
+            "line_prefix": "> ",
+            "template": "{title}",
+            "sticky_first_toast": true,
+            "force_aumid": "App",
+            "show_listening_time": true,
+            "toast": { "gap_ms": 2 }
+        });
+        migrate_config_value(&mut config_value);
+        assert_eq!(
+            config_value,
+            serde_json::json!({
+                "show_listening_time": true,
+                "toast": {
+                    "duration_ms": 5000,
+                    "gap_ms": 2,
+                    "line_prefix": "> ",
+                    "template": "{title}",
+                    "sticky_first": true,
+                    "force_aumid": "App"
+                }
+            })
+        );
+        let config = serde_json::from_value::<Config>(config_value).unwrap();
+        assert_eq!(config.toast.duration_ms, 5000);
+        assert_eq!(config.toast.template.as_deref(), Some("{title}"));
+        assert!(config.toast.sticky_first);
+        let mut config_value = serde_json::json!({ "show_listening_time": true });
+        migrate_config_value(&mut config_value);
+        assert_eq!(config_value, serde_json::json!({ "show_listening_time": true }));
+        for (old_name, new_name) in LEGACY_TOAST_FIELDS {
+            let mut config_value = serde_json::json!({ *old_name: null });
+            migrate_config_value(&mut config_value);
+            assert_eq!(config_value, serde_json::json!({ "toast": { *new_name: null } }));
+        }
+    }
+}